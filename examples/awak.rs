@@ -1,8 +1,7 @@
-use dns_resolver::Resolver;
-use std::io;
-
 #[cfg(feature = "awak-runtime")]
-fn main() -> io::Result<()> {
+fn main() -> std::io::Result<()> {
+    use dns_resolver::Resolver;
+
     let resolver = Resolver::new();
 
     awak::block_on(async {
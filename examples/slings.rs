@@ -1,9 +1,7 @@
-use std::io;
-
-use dns_resolver::Resolver;
-
 #[cfg(feature = "slings-runtime")]
-fn main() -> io::Result<()> {
+fn main() -> std::io::Result<()> {
+    use dns_resolver::Resolver;
+
     let resolver = Resolver::new();
 
     slings::block_on(async {
@@ -0,0 +1,662 @@
+//! Caching of answers.
+//!
+//! The resolver keeps answers in a cache implementing the [`Cache`] trait.
+//! Answers are cached per question, i.e., per combination of query name,
+//! query type, and query class. Answers to queries with a client subnet are
+//! additionally kept apart by subnet unless their scope covers all clients.
+//!
+//! The default implementation, [`LruCache`], keeps a limited number of
+//! answers. Each entry expires after the smallest TTL of the records in the
+//! answer’s answer section, clamped to the limits set in the resolver
+//! options.
+//!
+//! Negative answers, i.e., NXDOMAIN and NODATA, are cached as described in
+//! RFC 2308 for the TTL derived from the SOA record in their authority
+//! section. Negative answers without an SOA record are not cached.
+//!
+//! When an answer is taken from the cache, the TTLs of its records are
+//! reduced by the time it has spent there.
+//!
+//! If serving stale answers as described in RFC 8767 is enabled, expired
+//! entries are kept for a while longer and returned with a short TTL when
+//! none of the upstream servers can provide an answer.
+//!
+//! If prefetching is enabled, the cache keeps track of how often each entry
+//! is used. Popular entries close to expiring are refreshed in the
+//! background so that callers don’t have to wait for them.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use domain::base::iana::{Class, Rtype};
+use domain::base::message::Message;
+use domain::base::message_builder::MessageBuilder;
+use domain::base::name::{Name, ParsedName, ToName};
+use domain::base::opt::subnet::ClientSubnet;
+use domain::base::question::Question;
+use domain::base::record::Ttl;
+use domain::rdata::{AllRecordData, Soa};
+
+use crate::conf::ResolvOptions;
+use crate::Answer;
+
+/// The TTL of stale answers as recommended by RFC 8767.
+const STALE_ANSWER_TTL: Ttl = Ttl::from_secs(30);
+
+//------------ Cache ---------------------------------------------------------
+
+/// A cache of answers.
+///
+/// Implementations decide how long answers are kept. Since a cache may be
+/// shared between several resolvers, all methods take `&self`.
+pub trait Cache: Send + Sync {
+    /// Returns the answer for `key` if there is one.
+    fn get(&self, key: &CacheKey) -> Option<Answer>;
+
+    /// Returns the answer for `key` even if it has expired.
+    ///
+    /// This is used to serve stale answers when no upstream server can
+    /// provide an answer. The default implementation never returns stale
+    /// answers.
+    fn get_stale(&self, _key: &CacheKey) -> Option<Answer> {
+        None
+    }
+
+    /// Returns whether the answer for `key` should be refreshed now.
+    ///
+    /// This is asked after `get` returned an answer. Implementations should
+    /// return `true` at most once per entry. The default implementation
+    /// never asks for a refresh.
+    fn should_prefetch(&self, _key: &CacheKey) -> bool {
+        false
+    }
+
+    /// Adds `answer` as the answer for `key`.
+    fn insert(&self, key: CacheKey, answer: Answer);
+
+    /// Removes the answer for `key`.
+    fn remove(&self, key: &CacheKey);
+
+    /// Removes all answers.
+    fn clear(&self);
+
+    /// Returns the number of answers in the cache.
+    fn len(&self) -> usize;
+
+    /// Returns whether the cache is empty.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+//------------ CacheKey ------------------------------------------------------
+
+/// The key of a cache entry.
+///
+/// Since domain names compare case-insensitively, so do keys.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct CacheKey {
+    qname: Name<Vec<u8>>,
+    qtype: Rtype,
+    qclass: Class,
+    subnet: Option<ClientSubnet>,
+}
+
+impl CacheKey {
+    /// Creates a new key from its components.
+    pub fn new<N: ToName>(qname: &N, qtype: Rtype, qclass: Class) -> Self {
+        CacheKey {
+            qname: qname.to_name(),
+            qtype,
+            qclass,
+            subnet: None,
+        }
+    }
+
+    /// Returns the key for a query sent with the client subnet `subnet`.
+    ///
+    /// The scope prefix length of `subnet` is ignored.
+    pub fn with_client_subnet(mut self, subnet: Option<ClientSubnet>) -> Self {
+        self.subnet =
+            subnet.map(|subnet| ClientSubnet::new(subnet.source_prefix_len(), 0, subnet.addr()));
+        self
+    }
+
+    /// Returns the key for an answer with the scope prefix length `scope`.
+    ///
+    /// An answer with a scope of zero is valid for all subnets of the
+    /// address family, so its key is the same for all of them. Others are
+    /// kept under the client subnet of the query.
+    pub fn for_scope(&self, scope: u8) -> Self {
+        let mut res = self.clone();
+        if scope == 0 {
+            res.subnet = self
+                .subnet
+                .map(|subnet| ClientSubnet::new(0, 0, subnet.addr()));
+        }
+        res
+    }
+
+    /// Returns the query name.
+    pub fn qname(&self) -> &Name<Vec<u8>> {
+        &self.qname
+    }
+
+    /// Returns the query type.
+    pub fn qtype(&self) -> Rtype {
+        self.qtype
+    }
+
+    /// Returns the query class.
+    pub fn qclass(&self) -> Class {
+        self.qclass
+    }
+
+    /// Returns the client subnet of the query, if any.
+    pub fn client_subnet(&self) -> Option<ClientSubnet> {
+        self.subnet
+    }
+}
+
+impl<'a, N: ToName> From<&'a Question<N>> for CacheKey {
+    fn from(question: &'a Question<N>) -> Self {
+        CacheKey::new(question.qname(), question.qtype(), question.qclass())
+    }
+}
+
+//------------ LruCache ------------------------------------------------------
+
+/// An LRU cache of answers that honors record TTLs.
+///
+/// This is the cache used by a resolver unless told otherwise.
+pub struct LruCache {
+    entries: Mutex<lru_time_cache::LruCache<CacheKey, CacheEntry>>,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    max_negative_ttl: Duration,
+    stale_window: Duration,
+    prefetch: Option<(usize, f64)>,
+}
+
+impl LruCache {
+    /// Creates a new cache using the cache options of `options`.
+    pub fn new(options: &ResolvOptions) -> Self {
+        LruCache {
+            entries: Mutex::new(lru_time_cache::LruCache::with_capacity(options.cache_size)),
+            min_ttl: options.cache_min_ttl,
+            max_ttl: options.cache_max_ttl,
+            max_negative_ttl: options.cache_max_negative_ttl,
+            stale_window: if options.serve_stale {
+                options.cache_max_stale
+            } else {
+                Duration::ZERO
+            },
+            prefetch: if options.prefetch {
+                let fraction = match options.prefetch_ttl_fraction {
+                    fraction if fraction.is_nan() => 0.0,
+                    fraction => fraction.clamp(0.0, 1.0),
+                };
+                Some((options.prefetch_min_hits, fraction))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Returns the answer for `key` if it is fresh or, if `stale` is set,
+    /// within the stale window.
+    ///
+    /// Entries past the stale window are removed.
+    fn lookup(&self, key: &CacheKey, stale: bool) -> Option<Answer> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        match entries.get_mut(key) {
+            Some(entry) if entry.expires > now => {
+                entry.hits += 1;
+                let elapsed = Ttl::from_duration_lossy(now - entry.inserted);
+                return Some(adjust_ttls(&entry.answer, |ttl| {
+                    ttl.saturating_sub(elapsed)
+                }));
+            }
+            Some(entry) if entry.expires + self.stale_window > now => {
+                if stale {
+                    return Some(adjust_ttls(&entry.answer, |_| STALE_ANSWER_TTL));
+                }
+                return None;
+            }
+            Some(_) => {}
+            None => return None,
+        }
+        entries.remove(key);
+        None
+    }
+}
+
+impl Cache for LruCache {
+    /// Returns the answer for `key` if there is one and it hasn’t expired.
+    ///
+    /// The TTLs of the records in the returned answer are reduced by the
+    /// time the answer has spent in the cache.
+    fn get(&self, key: &CacheKey) -> Option<Answer> {
+        self.lookup(key, false)
+    }
+
+    /// Returns the answer for `key` if it is within the stale window.
+    ///
+    /// The TTLs of the records in the returned answer are set to 30 seconds
+    /// if the answer has expired.
+    fn get_stale(&self, key: &CacheKey) -> Option<Answer> {
+        self.lookup(key, true)
+    }
+
+    /// Returns whether the entry for `key` is popular and about to expire.
+    ///
+    /// An entry is popular if it has been used at least `prefetch_min_hits`
+    /// times. It is about to expire if less than `prefetch_ttl_fraction` of
+    /// its TTL is left.
+    fn should_prefetch(&self, key: &CacheKey) -> bool {
+        let (min_hits, fraction) = match self.prefetch {
+            Some(prefetch) => prefetch,
+            None => return false,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        match entries.get_mut(key) {
+            Some(entry) if !entry.prefetching && entry.hits >= min_hits && entry.expires > now => {
+                let ttl = entry.expires - entry.inserted;
+                entry.prefetching = entry.expires - now < ttl.mul_f64(fraction);
+                entry.prefetching
+            }
+            _ => false,
+        }
+    }
+
+    /// Adds `answer` to the cache.
+    ///
+    /// Answers that are not final are not cached.
+    fn insert(&self, key: CacheKey, answer: Answer) {
+        if !answer.is_final() {
+            return;
+        }
+        let ttl = if answer.negative().is_some() {
+            negative_ttl(&answer).map(|ttl| ttl.max(self.min_ttl).min(self.max_negative_ttl))
+        } else {
+            answer_ttl(&answer).map(|ttl| ttl.max(self.min_ttl).min(self.max_ttl))
+        };
+        let ttl = match ttl {
+            Some(ttl) => ttl,
+            None => return,
+        };
+        if ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let entry = CacheEntry {
+            answer,
+            inserted: now,
+            expires: now + ttl,
+            hits: 0,
+            prefetching: false,
+        };
+        self.entries.lock().unwrap().insert(key, entry);
+    }
+
+    fn remove(&self, key: &CacheKey) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+//------------ NoCache -------------------------------------------------------
+
+/// A cache that never keeps anything.
+///
+/// Use this to disable caching altogether.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoCache;
+
+impl Cache for NoCache {
+    fn get(&self, _key: &CacheKey) -> Option<Answer> {
+        None
+    }
+
+    fn insert(&self, _key: CacheKey, _answer: Answer) {}
+
+    fn remove(&self, _key: &CacheKey) {}
+
+    fn clear(&self) {}
+
+    fn len(&self) -> usize {
+        0
+    }
+}
+
+//------------ CacheEntry ----------------------------------------------------
+
+/// A cached answer and its bookkeeping.
+struct CacheEntry {
+    answer: Answer,
+    inserted: Instant,
+    expires: Instant,
+
+    /// How often the answer has been returned by `get`.
+    hits: usize,
+
+    /// Whether a refresh has been requested.
+    prefetching: bool,
+}
+
+//------------ Private Helpers -----------------------------------------------
+
+/// Returns the smallest TTL of the records in the answer section.
+fn answer_ttl(answer: &Answer) -> Option<Duration> {
+    answer
+        .answer()
+        .ok()?
+        .flatten()
+        .map(|record| record.ttl().into_duration())
+        .min()
+}
+
+/// Returns a copy of `answer` with the TTLs of all records changed by `op`.
+///
+/// The OPT record is left alone since its TTL field contains flags. If the
+/// answer can’t be copied, it is returned unchanged.
+fn adjust_ttls<F: Fn(Ttl) -> Ttl>(answer: &Answer, op: F) -> Answer {
+    let copy = || {
+        let mut target = MessageBuilder::new_vec()
+            .start_answer(answer.as_ref(), answer.header().rcode())
+            .ok()?;
+        *target.header_mut() = answer.header();
+        let target = answer
+            .copy_records(target, |record| {
+                let mut record = record
+                    .into_any_record::<AllRecordData<_, ParsedName<_>>>()
+                    .ok()?;
+                if record.rtype() != Rtype::OPT {
+                    record.set_ttl(op(record.ttl()));
+                }
+                Some(record)
+            })
+            .ok()?;
+        Some(Message::from_octets(target.finish()).ok()?.into())
+    };
+    copy().unwrap_or_else(|| answer.clone())
+}
+
+/// Returns the TTL of a negative answer.
+///
+/// This is the smaller of the TTL of the SOA record in the authority
+/// section and its MINIMUM field.
+fn negative_ttl(answer: &Answer) -> Option<Duration> {
+    let record = answer.authority().ok()?.limit_to::<Soa<_>>().next()?.ok()?;
+    Some(record.ttl().min(record.data().minimum()).into_duration())
+}
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::NegativeAnswer;
+    use domain::base::iana::Rcode;
+    use domain::base::Serial;
+    use domain::rdata::A;
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    fn answer(qname: &Name<Vec<u8>>, ttls: &[u32]) -> Answer {
+        let mut message = MessageBuilder::new_vec().question();
+        message.header_mut().set_qr(true);
+        message.push((qname, Rtype::A)).unwrap();
+        let mut message = message.answer();
+        for &ttl in ttls {
+            message
+                .push((qname, ttl, A::new(Ipv4Addr::LOCALHOST)))
+                .unwrap();
+        }
+        message.into_message().into()
+    }
+
+    fn negative(qname: &Name<Vec<u8>>, rcode: Rcode, soa: Option<(u32, u32)>) -> Answer {
+        let mut message = MessageBuilder::new_vec().question();
+        message.header_mut().set_qr(true);
+        message.header_mut().set_rcode(rcode);
+        message.push((qname, Rtype::A)).unwrap();
+        let mut message = message.authority();
+        if let Some((ttl, minimum)) = soa {
+            let soa = Soa::new(
+                qname,
+                qname,
+                Serial(1),
+                Ttl::from_secs(3600),
+                Ttl::from_secs(600),
+                Ttl::from_secs(86400),
+                Ttl::from_secs(minimum),
+            );
+            message.push((qname, ttl, soa)).unwrap();
+        }
+        message.into_message().into()
+    }
+
+    #[test]
+    fn expiry_from_ttl() {
+        let options = ResolvOptions {
+            cache_max_ttl: Duration::from_secs(30),
+            ..Default::default()
+        };
+        let cache = LruCache::new(&options);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        let key = CacheKey::new(&qname, Rtype::A, Class::IN);
+
+        cache.insert(key.clone(), answer(&qname, &[]));
+        assert!(cache.get(&key).is_none());
+        cache.insert(key.clone(), answer(&qname, &[0]));
+        assert!(cache.get(&key).is_none());
+
+        let now = Instant::now();
+        cache.insert(key.clone(), answer(&qname, &[10, 5]));
+        let expires = cache.entries.lock().unwrap().peek(&key).unwrap().expires;
+        assert!(expires >= now + Duration::from_secs(5));
+        assert!(expires < now + Duration::from_secs(6));
+
+        cache.insert(key.clone(), answer(&qname, &[3600]));
+        let expires = cache.entries.lock().unwrap().peek(&key).unwrap().expires;
+        assert!(expires < now + Duration::from_secs(31));
+
+        let upper = Name::<Vec<u8>>::from_str("EXAMPLE.com").unwrap();
+        assert!(cache
+            .get(&CacheKey::new(&upper, Rtype::A, Class::IN))
+            .is_some());
+        assert!(cache
+            .get(&CacheKey::new(&qname, Rtype::AAAA, Class::IN))
+            .is_none());
+    }
+
+    #[test]
+    fn ttl_decrement() {
+        let cache = LruCache::new(&ResolvOptions::default());
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        let key = CacheKey::new(&qname, Rtype::A, Class::IN);
+        cache.insert(key.clone(), answer(&qname, &[60, 30]));
+        if let Some(entry) = cache.entries.lock().unwrap().get_mut(&key) {
+            entry.inserted -= Duration::from_secs(10);
+        }
+        let answer = cache.get(&key).unwrap();
+        let ttls: Vec<_> = answer
+            .answer()
+            .unwrap()
+            .flatten()
+            .map(|record| record.ttl().as_secs())
+            .collect();
+        assert_eq!(ttls, [50, 20]);
+    }
+
+    #[test]
+    fn stale_answers() {
+        let options = ResolvOptions {
+            serve_stale: true,
+            ..Default::default()
+        };
+        let cache = LruCache::new(&options);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        let key = CacheKey::new(&qname, Rtype::A, Class::IN);
+        cache.insert(key.clone(), answer(&qname, &[60]));
+        if let Some(entry) = cache.entries.lock().unwrap().get_mut(&key) {
+            entry.inserted -= Duration::from_secs(120);
+            entry.expires -= Duration::from_secs(120);
+        }
+        assert!(cache.get(&key).is_none());
+        let answer = cache.get_stale(&key).unwrap();
+        let record = answer.answer().unwrap().next().unwrap().unwrap();
+        assert_eq!(record.ttl(), STALE_ANSWER_TTL);
+
+        if let Some(entry) = cache.entries.lock().unwrap().get_mut(&key) {
+            entry.expires -= options.cache_max_stale;
+        }
+        assert!(cache.get_stale(&key).is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn prefetch_popular() {
+        let options = ResolvOptions {
+            prefetch: true,
+            prefetch_min_hits: 2,
+            ..Default::default()
+        };
+        let cache = LruCache::new(&options);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        let key = CacheKey::new(&qname, Rtype::A, Class::IN);
+        cache.insert(key.clone(), answer(&qname, &[100]));
+        cache.get(&key).unwrap();
+        cache.get(&key).unwrap();
+        assert!(!cache.should_prefetch(&key));
+
+        if let Some(entry) = cache.entries.lock().unwrap().get_mut(&key) {
+            entry.inserted -= Duration::from_secs(95);
+            entry.expires -= Duration::from_secs(95);
+        }
+        assert!(cache.should_prefetch(&key));
+        assert!(!cache.should_prefetch(&key));
+    }
+
+    #[test]
+    fn prefetch_fraction_out_of_range() {
+        for &(fraction, prefetch) in &[(-1.0, false), (f64::NAN, false), (2.0, true)] {
+            let options = ResolvOptions {
+                prefetch: true,
+                prefetch_min_hits: 0,
+                prefetch_ttl_fraction: fraction,
+                ..Default::default()
+            };
+            let cache = LruCache::new(&options);
+            let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+            let key = CacheKey::new(&qname, Rtype::A, Class::IN);
+            cache.insert(key.clone(), answer(&qname, &[100]));
+            assert_eq!(cache.should_prefetch(&key), prefetch);
+        }
+    }
+
+    #[test]
+    fn negative_expiry_from_soa() {
+        let cache = LruCache::new(&ResolvOptions::default());
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        let key = CacheKey::new(&qname, Rtype::A, Class::IN);
+
+        cache.insert(key.clone(), negative(&qname, Rcode::NXDOMAIN, None));
+        assert!(cache.get(&key).is_none());
+
+        let now = Instant::now();
+        cache.insert(
+            key.clone(),
+            negative(&qname, Rcode::NXDOMAIN, Some((60, 30))),
+        );
+        let expires = cache.entries.lock().unwrap().peek(&key).unwrap().expires;
+        assert!(expires >= now + Duration::from_secs(30));
+        assert!(expires < now + Duration::from_secs(31));
+        let answer = cache.get(&key).unwrap();
+        assert_eq!(answer.negative(), Some(NegativeAnswer::NxDomain));
+
+        cache.insert(
+            key.clone(),
+            negative(&qname, Rcode::NOERROR, Some((20, 30))),
+        );
+        let expires = cache.entries.lock().unwrap().peek(&key).unwrap().expires;
+        assert!(expires < now + Duration::from_secs(21));
+        let answer = cache.get(&key).unwrap();
+        assert_eq!(answer.negative(), Some(NegativeAnswer::NoData));
+    }
+
+    #[test]
+    fn min_above_max() {
+        let options = ResolvOptions {
+            cache_min_ttl: Duration::from_secs(2 * 60 * 60),
+            cache_max_ttl: Duration::from_secs(60 * 60),
+            ..Default::default()
+        };
+        let cache = LruCache::new(&options);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        let key = CacheKey::new(&qname, Rtype::A, Class::IN);
+
+        let now = Instant::now();
+        cache.insert(key.clone(), answer(&qname, &[60]));
+        let expires = cache.entries.lock().unwrap().peek(&key).unwrap().expires;
+        assert!(expires >= now + options.cache_max_ttl);
+        assert!(expires < now + options.cache_max_ttl + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn negative_min_above_max() {
+        let options = ResolvOptions {
+            cache_min_ttl: Duration::from_secs(4 * 60 * 60),
+            ..Default::default()
+        };
+        let cache = LruCache::new(&options);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        let key = CacheKey::new(&qname, Rtype::A, Class::IN);
+
+        let now = Instant::now();
+        cache.insert(
+            key.clone(),
+            negative(&qname, Rcode::NXDOMAIN, Some((60, 30))),
+        );
+        let expires = cache.entries.lock().unwrap().peek(&key).unwrap().expires;
+        assert!(expires >= now + options.cache_max_negative_ttl);
+        assert!(expires < now + options.cache_max_negative_ttl + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn client_subnet_scope() {
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        let key = CacheKey::new(&qname, Rtype::A, Class::IN);
+        assert_eq!(key.for_scope(24), key);
+        assert_eq!(key.for_scope(0), key);
+
+        let subnet =
+            |addr: [u8; 4], scope| ClientSubnet::new(24, scope, Ipv4Addr::from(addr).into());
+        let first = key
+            .clone()
+            .with_client_subnet(Some(subnet([192, 0, 2, 1], 16)));
+        let second = key
+            .clone()
+            .with_client_subnet(Some(subnet([192, 0, 2, 99], 0)));
+        let other = key
+            .clone()
+            .with_client_subnet(Some(subnet([198, 51, 100, 1], 0)));
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+        assert_ne!(first, key);
+        assert_eq!(first.client_subnet().unwrap().scope_prefix_len(), 0);
+        assert_eq!(first.for_scope(24), first);
+        assert_eq!(first.for_scope(0), other.for_scope(0));
+        assert_ne!(first.for_scope(0), key);
+    }
+}
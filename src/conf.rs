@@ -17,6 +17,8 @@ use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::Path;
 use std::slice::SliceIndex;
 use std::str::{self, FromStr, SplitWhitespace};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
 use std::time::Duration;
 use std::vec::Vec;
 use std::{convert, error, fmt, fs, io, ops};
@@ -82,29 +84,35 @@ pub struct ResolvOptions {
     ///
     /// Enabled by default.
     ///
-    /// This is not currently implemented. Instead, the resolver config’s
-    /// `search` and `ndots` fields govern resolution of relative names of
-    /// all kinds.
+    /// This option is implemented by `search()` and `lookup_host()`. If
+    /// disabled, names without any dots are not tried with the suffixes of
+    /// the `search` list.
     pub default_names: bool,
 
     /// Keep TCP connections open between queries.
     ///
-    /// This is not currently implemented.
+    /// This option is implemented by servers using the TCP or TLS transport.
+    /// Each keeps a single connection open, or one per thread with the
+    /// slings runtime, and sends all its queries over it, possibly several
+    /// at the same time. Queries carry the edns-tcp-keepalive option of
+    /// RFC 7828 and an idle connection is closed after the idle timeout the
+    /// server announces in its answers or `tcp_idle_timeout`.
     pub stay_open: bool,
 
     /// Search hostnames in the current domain and parent domains.
     ///
     /// Enabled by default.
     ///
-    /// This options is not currently implemented. Instead, the resolver
-    /// config’s `search` and `ndots` fields govern resolution of relative
-    /// names.
+    /// This option is implemented by `search()` and `lookup_host()`. If
+    /// disabled, names with dots are not tried with the suffixes of the
+    /// `search` list.
     pub dn_search: bool,
 
     /// Try AAAA query before A query and map IPv4 responses to tunnel form.
     ///
-    /// This option is not currently implemented. It is only relevant for
-    /// `lookup_host`.
+    /// This option is only relevant for `lookup_host()` which places the
+    /// IPv6 addresses before the IPv4 addresses in its result when it is
+    /// set. Mapping IPv4 addresses to tunnel form is not implemented.
     pub use_inet6: bool,
 
     /// Use round-robin selection of name servers.
@@ -126,7 +134,8 @@ pub struct ResolvOptions {
 
     /// Send each query simultaneously to all name servers.
     ///
-    /// This is not currently implemented. It would be a query option.
+    /// This option is implemented by `Query`. The first final answer is
+    /// used and the queries to the other servers are abandoned.
     pub blast: bool,
 
     /// Use bit-label format for IPv6 reverse lookups.
@@ -140,18 +149,23 @@ pub struct ResolvOptions {
     /// (This option is the reverse of glibc’s `RES_NOIP6DOTINT` option).
     ///
     /// This option is only relevant for `lookup_addr()` and is implemented
-    /// there already.
+    /// there.
     pub use_ip6dotint: bool,
 
     /// Use EDNS0.
     ///
-    /// EDNS is not yet supported.
+    /// Disabled by default as with glibc. Note that earlier versions of this
+    /// resolver used EDNS regardless of this option. Set it, for instance
+    /// through `options edns0` in resolv.conf, to keep doing so.
+    ///
+    /// Queries include an OPT record with the server’s UDP payload size and
+    /// a DNS cookie. Queries made with their own EDNS settings use EDNS
+    /// regardless of this option.
     pub use_edns0: bool,
 
     /// Perform IPv4 and IPv6 lookups sequentially instead of in parallel.
     ///
-    /// This is not yet implemented but would be an option for
-    /// `lookup_host()`.
+    /// This option is implemented by `lookup_host()`.
     pub single_request: bool,
 
     /// Open a new socket for each request.
@@ -161,10 +175,103 @@ pub struct ResolvOptions {
 
     /// Don’t look up unqualified names as top-level-domain.
     ///
-    /// This is not currently implemented. Instead, the resolver config’s
-    /// `search` and `ndots` fields govern resolution of relative names of
-    /// all kinds.
+    /// This option is implemented by `search()` and `lookup_host()`.
     pub no_tld_query: bool,
+
+    /// Randomize the case of the query name as an extra defense against
+    /// spoofed answers.
+    ///
+    /// Each attempt sends the query name with the case of its letters
+    /// picked at random and a UDP response has to repeat it exactly. This
+    /// is known as DNS 0x20. Responses with the wrong case are dropped. If
+    /// no response with the right case arrives, the query is repeated
+    /// without randomizing the case. Servers for which this happens several
+    /// queries in a row are queried without it for a while. This is not a
+    /// glibc option.
+    pub randomize_case: bool,
+
+    /// Send queries to the fastest server first.
+    ///
+    /// The servers are ordered by the smoothed round-trip time of their
+    /// answers, with servers that failed to answer their last query moved
+    /// to the end. A small share of queries goes to another server first to
+    /// keep the times current. This overrides `rotate`. This is not a glibc
+    /// option.
+    pub prefer_fastest: bool,
+
+    /// How long to do without EDNS for a server that didn’t support it.
+    ///
+    /// Once this time has passed, EDNS is tried again with the server. This
+    /// is not a glibc option. It defaults to 15 minutes.
+    pub edns_retry_interval: Duration,
+
+    /// How long to keep an idle TCP connection open.
+    ///
+    /// This is used with `stay_open` for servers that don’t announce an
+    /// idle timeout of their own. This is not a glibc option. It defaults
+    /// to 10 seconds.
+    pub tcp_idle_timeout: Duration,
+
+    /// Cache the answers to all queries.
+    ///
+    /// The answers to `lookup_host()` and `lookup_addr()` are always cached.
+    /// If this option is set, the answers to all other queries are cached,
+    /// too. This is not a glibc option.
+    pub cache_queries: bool,
+
+    /// Maximum number of answers kept in the cache.
+    ///
+    /// This is not a glibc option. It defaults to 1024 answers.
+    pub cache_size: usize,
+
+    /// Minimum time an answer is kept in the cache.
+    ///
+    /// Answers with smaller TTLs are cached for this long regardless. This
+    /// is not a glibc option. It defaults to zero.
+    pub cache_min_ttl: Duration,
+
+    /// Maximum time an answer is kept in the cache.
+    ///
+    /// Answers with larger TTLs expire after this long. This takes precedence
+    /// over `cache_min_ttl`. This is not a glibc option. It defaults to one
+    /// day.
+    pub cache_max_ttl: Duration,
+
+    /// Maximum time a negative answer is kept in the cache.
+    ///
+    /// This takes precedence over `cache_min_ttl`. This is not a glibc
+    /// option. It defaults to three hours as suggested by RFC 2308.
+    pub cache_max_negative_ttl: Duration,
+
+    /// Serve expired answers from the cache if upstream servers fail.
+    ///
+    /// If all upstream servers time out or fail, an expired answer that is
+    /// still within `cache_max_stale` is returned with a TTL of 30 seconds
+    /// as described in RFC 8767. This is not a glibc option.
+    pub serve_stale: bool,
+
+    /// How long expired answers are kept for serving them stale.
+    ///
+    /// This is only relevant if `serve_stale` is set. This is not a glibc
+    /// option. It defaults to one day.
+    pub cache_max_stale: Duration,
+
+    /// Refresh popular cached answers in the background before they expire.
+    ///
+    /// This is not a glibc option.
+    pub prefetch: bool,
+
+    /// How often a cached answer needs to be used to be prefetched.
+    ///
+    /// This is not a glibc option. It defaults to 3.
+    pub prefetch_min_hits: usize,
+
+    /// Fraction of the TTL left when a cached answer is prefetched.
+    ///
+    /// Values outside of zero to one are limited to that range. This is not
+    /// a glibc option. It defaults to 0.1, i.e., an answer is refreshed
+    /// during the last ten percent of its TTL.
+    pub prefetch_ttl_fraction: f64,
 }
 
 impl Default for ResolvOptions {
@@ -199,6 +306,22 @@ impl Default for ResolvOptions {
             single_request: false,
             single_request_reopen: false,
             no_tld_query: false,
+            randomize_case: false,
+            prefer_fastest: false,
+            edns_retry_interval: Duration::from_secs(15 * 60),
+            tcp_idle_timeout: Duration::from_secs(10),
+
+            // cache:
+            cache_queries: false,
+            cache_size: 1024,
+            cache_min_ttl: Duration::ZERO,
+            cache_max_ttl: Duration::from_secs(24 * 60 * 60),
+            cache_max_negative_ttl: Duration::from_secs(3 * 60 * 60),
+            serve_stale: false,
+            cache_max_stale: Duration::from_secs(24 * 60 * 60),
+            prefetch: false,
+            prefetch_min_hits: 3,
+            prefetch_ttl_fraction: 0.1,
         }
     }
 }
@@ -206,13 +329,40 @@ impl Default for ResolvOptions {
 //------------ Transport -----------------------------------------------------
 
 /// The transport protocol to be used for a server.
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Transport {
     /// Unencrypted UDP transport.
     Udp,
 
     /// Unencrypted TCP transport.
     Tcp,
+
+    /// DNS over TLS as defined in RFC 7858.
+    ///
+    /// The server’s certificate is checked against `ServerConf::tls_name`.
+    /// Connections are only reused with the `stay_open` option.
+    #[cfg(feature = "tls")]
+    Tls,
+
+    /// DNS over HTTPS as defined in RFC 8484.
+    ///
+    /// The value is the URI template of the server, for instance
+    /// `https://dns.example/dns-query{?dns}`. If the template contains the
+    /// `dns` variable, queries are sent via GET, otherwise via POST. The
+    /// connection is made to the server’s address, the host of the template
+    /// is only used for the HTTP request and for verifying the certificate
+    /// unless `ServerConf::tls_name` is set. An `http` template can be used
+    /// if TLS is terminated by a proxy in front of the resolver. An egress
+    /// proxy can be set with `ServerConf::https_proxy`.
+    #[cfg(feature = "https")]
+    Https(String),
+
+    /// DNS over QUIC as defined in RFC 9250.
+    ///
+    /// The server’s certificate is checked against `ServerConf::tls_name`.
+    /// This transport is only available with the Tokio runtime.
+    #[cfg(feature = "quic")]
+    Quic,
 }
 
 impl Transport {
@@ -221,18 +371,83 @@ impl Transport {
     /// Only preferred transports are considered initially. Only if a
     /// truncated answer comes back will we consider streaming protocols
     /// instead.
-    pub fn is_preferred(self) -> bool {
+    pub fn is_preferred(&self) -> bool {
         match self {
             Transport::Udp => true,
             Transport::Tcp => false,
+            #[cfg(feature = "tls")]
+            Transport::Tls => false,
+            #[cfg(feature = "https")]
+            Transport::Https(_) => false,
+            #[cfg(feature = "quic")]
+            Transport::Quic => false,
         }
     }
 
     /// Returns whether the transport is a streaming protocol.
-    pub fn is_stream(self) -> bool {
+    pub fn is_stream(&self) -> bool {
         match self {
             Transport::Udp => false,
             Transport::Tcp => true,
+            #[cfg(feature = "tls")]
+            Transport::Tls => true,
+            #[cfg(feature = "https")]
+            Transport::Https(_) => true,
+            #[cfg(feature = "quic")]
+            Transport::Quic => true,
+        }
+    }
+
+    /// Returns whether the transport is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        match self {
+            Transport::Udp => false,
+            Transport::Tcp => false,
+            #[cfg(feature = "tls")]
+            Transport::Tls => true,
+            #[cfg(feature = "https")]
+            Transport::Https(_) => true,
+            #[cfg(feature = "quic")]
+            Transport::Quic => true,
+        }
+    }
+}
+
+//------------ Padding -------------------------------------------------------
+
+/// How to pad queries sent over encrypted transports.
+///
+/// Padding as defined in RFC 7830 hides the length of the query name from
+/// someone watching the encrypted traffic.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Padding {
+    /// Queries are not padded.
+    None,
+
+    /// Queries are padded to a multiple of the given block length.
+    ///
+    /// RFC 8467 recommends a block length of 128 octets for queries.
+    BlockLength(u16),
+}
+
+impl Padding {
+    /// Returns the length of the padding for a message of `len` octets.
+    ///
+    /// The length of the message has to include the four octets of the
+    /// header of the padding option. Returns `None` if no padding option
+    /// should be added, which includes the case that the padded message
+    /// would be longer than a DNS message can be.
+    pub fn padding_len(self, len: usize) -> Option<u16> {
+        match self {
+            Padding::None | Padding::BlockLength(0) => None,
+            Padding::BlockLength(block) => {
+                let block = usize::from(block);
+                let padding = (block - len % block) % block;
+                match len.checked_add(padding) {
+                    Some(padded) if padded <= usize::from(u16::MAX) => Some(padding as u16),
+                    _ => None,
+                }
+            }
         }
     }
 }
@@ -278,6 +493,37 @@ pub struct ServerConf {
     /// server. It will be included both for datagram and streaming transport
     /// but really only matters for UDP.
     pub udp_payload_size: u16,
+
+    /// How to pad queries.
+    ///
+    /// This is used for the encrypted transports only. Since padding needs
+    /// EDNS, queries to these servers include an OPT record unless padding
+    /// is disabled. It defaults to padding to blocks of 128 octets.
+    pub padding: Padding,
+
+    /// Name to verify the server’s TLS certificate against.
+    ///
+    /// This is used for the encrypted transports only. If it is not set, the
+    /// certificate has to be issued for the host of the URI template with
+    /// HTTPS or for the server’s IP address otherwise.
+    #[cfg(feature = "tls")]
+    pub tls_name: Option<String>,
+
+    /// TLS client configuration.
+    ///
+    /// This is used for the encrypted transports only. If it is not set,
+    /// server certificates are verified using the Mozilla root
+    /// certificates.
+    #[cfg(feature = "tls")]
+    pub tls_config: Option<Arc<rustls::ClientConfig>>,
+
+    /// Address of an HTTP proxy to reach the server through.
+    ///
+    /// This is used for the HTTPS transport only. If it is set, connections
+    /// are tunneled through the proxy with a `CONNECT` request for the
+    /// server’s address.
+    #[cfg(feature = "https")]
+    pub https_proxy: Option<SocketAddr>,
 }
 
 impl ServerConf {
@@ -292,6 +538,13 @@ impl ServerConf {
             request_timeout: Duration::from_secs(2),
             recv_size: 1232,
             udp_payload_size: 1232,
+            padding: Padding::BlockLength(128),
+            #[cfg(feature = "tls")]
+            tls_name: None,
+            #[cfg(feature = "tls")]
+            tls_config: None,
+            #[cfg(feature = "https")]
+            https_proxy: None,
         }
     }
 }
@@ -755,4 +1008,15 @@ mod test {
         assert!(conf.options.use_vc);
         assert_eq!(conf.options.ndots, 122);
     }
+
+    #[test]
+    fn padding_len() {
+        assert_eq!(Padding::None.padding_len(50), None);
+        assert_eq!(Padding::BlockLength(0).padding_len(50), None);
+        assert_eq!(Padding::BlockLength(128).padding_len(50), Some(78));
+        assert_eq!(Padding::BlockLength(128).padding_len(128), Some(0));
+        assert_eq!(Padding::BlockLength(128).padding_len(129), Some(127));
+        assert_eq!(Padding::BlockLength(128).padding_len(65500), None);
+        assert_eq!(Padding::BlockLength(128).padding_len(65408), Some(0));
+    }
 }
@@ -0,0 +1,92 @@
+//! EDNS settings for individual queries.
+
+use std::io;
+
+use domain::base::iana::OptionCode;
+use domain::base::message_builder::OptBuilder;
+use domain::base::opt::subnet::ClientSubnet;
+use domain::base::opt::{ComposeOptData, UnknownOptData};
+use domain::base::wire::Composer;
+
+//------------ EdnsOptions ---------------------------------------------------
+
+/// EDNS settings for a single query.
+///
+/// A query made with these settings uses EDNS even if the `use_edns0`
+/// option isn’t set, unless the server has turned out not to support it.
+/// Queries with the DNSSEC OK bit or further options aren’t cached since
+/// their answers depend on them.
+#[derive(Clone, Debug, Default)]
+pub struct EdnsOptions {
+    /// Set the DNSSEC OK bit to ask for DNSSEC records.
+    pub dnssec_ok: bool,
+
+    /// UDP payload size to advertise instead of the server’s.
+    pub udp_payload_size: Option<u16>,
+
+    /// The client subnet to send as defined in RFC 7871.
+    ///
+    /// Cached answers are kept apart by subnet. Answers the server marks
+    /// with a scope prefix length of zero are shared by all subnets. The
+    /// scope prefix length of the subnet given here is ignored.
+    pub client_subnet: Option<ClientSubnet>,
+
+    /// Further options to include in the OPT record.
+    options: Vec<UnknownOptData<Vec<u8>>>,
+}
+
+impl EdnsOptions {
+    /// Returns new default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an option with `code` and the already encoded `data`.
+    ///
+    /// Fails if the data is too long to fit into an option.
+    pub fn add_option(&mut self, code: OptionCode, data: impl Into<Vec<u8>>) -> io::Result<()> {
+        let option = UnknownOptData::new(code, data.into())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        self.options.push(option);
+        Ok(())
+    }
+
+    /// Adds an option given in its typed form.
+    pub fn add_typed_option(&mut self, option: &impl ComposeOptData) -> io::Result<()> {
+        let mut data = Vec::new();
+        option
+            .compose_option(&mut data)
+            .map_err(|_| io::Error::other("short buf"))?;
+        self.add_option(option.code(), data)
+    }
+
+    /// Returns the options added so far.
+    pub fn options(&self) -> impl Iterator<Item = (OptionCode, &[u8])> {
+        self.options
+            .iter()
+            .map(|option| (option.code(), option.as_slice()))
+    }
+
+    /// Returns whether the settings change the answer beyond the subnet.
+    pub(crate) fn is_cacheable(&self) -> bool {
+        !self.dnssec_ok && self.options.is_empty()
+    }
+
+    /// Applies the settings to the OPT record being built.
+    pub(crate) fn apply<Target: Composer>(
+        &self,
+        opt: &mut OptBuilder<'_, Target>,
+    ) -> Result<(), Target::AppendError> {
+        if let Some(size) = self.udp_payload_size {
+            opt.set_udp_payload_size(size);
+        }
+        opt.set_dnssec_ok(self.dnssec_ok);
+        if let Some(subnet) = self.client_subnet {
+            opt.client_subnet(subnet.source_prefix_len(), 0, subnet.addr())?;
+        }
+        for option in &self.options {
+            opt.push(option)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,450 @@
+//! DNS over HTTPS.
+//!
+//! Queries are sent to the server as `application/dns-message` requests as
+//! described in RFC 8484, either in the body of a POST request or base64url
+//! encoded in the `dns` parameter of a GET request. HTTP/2 is used if the
+//! server offers it during the TLS handshake, HTTP/1.1 otherwise. Response
+//! bodies longer than the maximum size of a DNS message are rejected.
+//!
+//! If the server is configured with a proxy, connections are tunneled
+//! through it using the HTTP `CONNECT` method.
+//!
+//! Connections are kept open and reused by later queries. There is one
+//! connection per thread as connections are driven by the runtime they
+//! were created in.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::Bytes;
+use domain::base::message::Message;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::client::conn::{http1, http2};
+use hyper::header::{HeaderValue, ACCEPT, CONTENT_TYPE, HOST};
+use hyper::rt::{Executor, ReadBufCursor};
+use hyper::{Method, Request, StatusCode, Uri};
+
+use crate::conf::ServerConf;
+use crate::tls::TlsClient;
+use crate::{spawn, Answer, QueryMessage, TcpStream};
+use crate::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The media type of DNS messages.
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// The maximum size of the head of a proxy’s response to `CONNECT`.
+const MAX_PROXY_HEAD: usize = 8192;
+
+/// The maximum size of a response body, which is that of a DNS message.
+const MAX_BODY: usize = 65535;
+
+thread_local! {
+    /// The idle connections of this thread by client.
+    static SENDERS: RefCell<HashMap<usize, Vec<Sender>>> = RefCell::new(HashMap::new());
+}
+
+/// The source of client identifiers.
+static NEXT_CLIENT: AtomicUsize = AtomicUsize::new(0);
+
+//------------ HttpsClient ---------------------------------------------------
+
+/// The HTTP client for a single server.
+#[derive(Debug)]
+pub(crate) struct HttpsClient {
+    id: usize,
+    addr: SocketAddr,
+    proxy: Option<SocketAddr>,
+    template: String,
+    authority: Option<String>,
+    tls: Option<TlsClient>,
+}
+
+impl HttpsClient {
+    pub fn new(conf: &ServerConf, template: &str) -> Self {
+        let base = expand(template, "").parse::<Uri>().ok();
+        let authority = base
+            .as_ref()
+            .and_then(|uri| uri.authority())
+            .map(|authority| authority.to_string());
+        let tls = match base {
+            Some(ref uri) if uri.scheme_str() == Some("http") => None,
+            _ => {
+                let name = conf
+                    .tls_name
+                    .clone()
+                    .or_else(|| base.as_ref().and_then(|uri| uri.host()).map(Into::into));
+                Some(TlsClient::with_alpn(
+                    conf,
+                    name,
+                    vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+                ))
+            }
+        };
+        HttpsClient {
+            id: NEXT_CLIENT.fetch_add(1, Ordering::Relaxed),
+            addr: conf.addr,
+            proxy: conf.https_proxy,
+            template: template.into(),
+            authority,
+            tls,
+        }
+    }
+
+    /// Sends `query` to the server and waits for its answer.
+    pub async fn query(&self, query: &QueryMessage) -> io::Result<Answer> {
+        let (sender, fresh) = self.sender().await?;
+        let res = match self.send(sender, query).await {
+            Err(err) if !fresh && err.kind() == io::ErrorKind::ConnectionAborted => {
+                let (sender, _) = self.sender().await?;
+                self.send(sender, query).await
+            }
+            res => res,
+        };
+        let body = res?;
+        let mut answer = Message::from_octets(body).map_err(|_| io::Error::other("short buf"))?;
+        answer.header_mut().set_id(query.header().id());
+        if !answer.is_answer(&query.as_message()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "answer does not match query",
+            ));
+        }
+        Ok(answer.into())
+    }
+
+    /// Sends the request for `query` and returns the response body.
+    async fn send(&self, mut sender: Sender, query: &QueryMessage) -> io::Result<Vec<u8>> {
+        let request = self.request(query, sender.is_http2())?;
+        let response = sender.send(request).await;
+        self.release(sender);
+        let response = response?;
+        if response.status() != StatusCode::OK {
+            return Err(io::Error::other(format!(
+                "server responded with {}",
+                response.status()
+            )));
+        }
+        if !response
+            .headers()
+            .get(CONTENT_TYPE)
+            .is_some_and(is_dns_message)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected content type",
+            ));
+        }
+        let body = Limited::new(response.into_body(), MAX_BODY)
+            .collect()
+            .await
+            .map_err(|err| {
+                if err.is::<LengthLimitError>() {
+                    io::Error::new(io::ErrorKind::InvalidData, "response too long")
+                } else {
+                    io::Error::other(err)
+                }
+            })?;
+        Ok(body.to_bytes().to_vec())
+    }
+
+    /// Builds the HTTP request for `query`.
+    ///
+    /// HTTP/1.1 requests use the origin form with a `Host` header, HTTP/2
+    /// requests the absolute URI.
+    ///
+    /// The message ID is zero on the wire so that responses to GET requests
+    /// can be cached by HTTP caches.
+    fn request(&self, query: &QueryMessage, http2: bool) -> io::Result<Request<Full<Bytes>>> {
+        let mut message = query.as_target().as_dgram_slice().to_vec();
+        message[..2].copy_from_slice(&[0, 0]);
+        let get = self.template.contains("{?dns}") || self.template.contains("{&dns}");
+        let uri = if get {
+            expand(&self.template, &URL_SAFE_NO_PAD.encode(&message))
+        } else {
+            expand(&self.template, "")
+        };
+        let uri = uri
+            .parse::<Uri>()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let mut request = Request::builder().header(ACCEPT, DNS_MESSAGE);
+        request = if http2 {
+            request.uri(uri)
+        } else {
+            let path = uri.path_and_query().map_or("/", |path| path.as_str());
+            request
+                .uri(path)
+                .header(HOST, self.authority.as_deref().unwrap_or_default())
+        };
+        let request = if get {
+            request.method(Method::GET).body(Full::default())
+        } else {
+            request
+                .method(Method::POST)
+                .header(CONTENT_TYPE, DNS_MESSAGE)
+                .body(Full::new(Bytes::from(message)))
+        };
+        request.map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+    }
+
+    /// Returns a sender for a request and whether its connection is new.
+    ///
+    /// An HTTP/2 connection is shared by all requests. An idle HTTP/1.1
+    /// connection is taken out of the pool until the request is done.
+    async fn sender(&self) -> io::Result<(Sender, bool)> {
+        let sender = SENDERS.with(|senders| {
+            let mut senders = senders.borrow_mut();
+            let idle = senders.entry(self.id).or_default();
+            idle.retain(|sender| !sender.is_closed());
+            match idle.last() {
+                Some(Sender::Http2(sender)) => Some(Sender::Http2(sender.clone())),
+                _ => idle.pop(),
+            }
+        });
+        match sender {
+            Some(sender) => Ok((sender, false)),
+            None => Ok((self.connect().await?, true)),
+        }
+    }
+
+    /// Keeps the connection of `sender` around for later requests.
+    fn release(&self, sender: Sender) {
+        SENDERS.with(|senders| {
+            let mut senders = senders.borrow_mut();
+            let idle = senders.entry(self.id).or_default();
+            match sender {
+                Sender::Http1(_) => idle.push(sender),
+                Sender::Http2(_) if idle.is_empty() => idle.push(sender),
+                Sender::Http2(_) => {}
+            }
+        })
+    }
+
+    /// Opens a new connection to the server.
+    async fn connect(&self) -> io::Result<Sender> {
+        let sock = match self.proxy {
+            Some(proxy) => self.tunnel(proxy).await?,
+            None => TcpStream::connect(&self.addr).await?,
+        };
+        let tls = match self.tls {
+            Some(ref tls) => tls,
+            None => return Sender::http1(Box::new(sock)).await,
+        };
+        let sock = tls.connect_over(sock).await?;
+        if sock.get_ref().1.alpn_protocol() == Some(b"h2") {
+            Sender::http2(Box::new(sock)).await
+        } else {
+            Sender::http1(Box::new(sock)).await
+        }
+    }
+
+    /// Opens a tunnel to the server through the HTTP proxy at `proxy`.
+    async fn tunnel(&self, proxy: SocketAddr) -> io::Result<TcpStream> {
+        let mut sock = TcpStream::connect(&proxy).await?;
+        let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", self.addr);
+        sock.write_all(request.as_bytes()).await?;
+
+        // Read the head byte by byte so nothing of the tunnel is consumed.
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_PROXY_HEAD {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "proxy response too long",
+                ));
+            }
+            let mut byte = [0u8];
+            sock.read_exact(&mut byte).await?;
+            head.push(byte[0]);
+        }
+        match head.split(|&ch| ch == b' ').nth(1) {
+            Some(status) if status.len() == 3 && status[0] == b'2' => Ok(sock),
+            _ => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "proxy refused connection",
+            )),
+        }
+    }
+}
+
+impl Drop for HttpsClient {
+    fn drop(&mut self) {
+        let _ = SENDERS.try_with(|senders| senders.borrow_mut().remove(&self.id));
+    }
+}
+
+/// Returns whether a `Content-Type` header value is the DNS message type.
+///
+/// Parameters after the media type are ignored.
+fn is_dns_message(value: &HeaderValue) -> bool {
+    let media_type = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.split(';').next());
+    media_type.is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case(DNS_MESSAGE))
+}
+
+/// Replaces the `dns` variable in the URI template with `value`.
+fn expand(template: &str, value: &str) -> String {
+    let (query, rest) = if value.is_empty() {
+        ("", "")
+    } else {
+        ("?dns=", "&dns=")
+    };
+    template
+        .replace("{?dns}", &format!("{}{}", query, value))
+        .replace("{&dns}", &format!("{}{}", rest, value))
+}
+
+//------------ Sender --------------------------------------------------------
+
+/// The sending side of a connection.
+enum Sender {
+    Http1(http1::SendRequest<Full<Bytes>>),
+    Http2(http2::SendRequest<Full<Bytes>>),
+}
+
+impl Sender {
+    async fn http1(sock: BoxStream) -> io::Result<Self> {
+        let (sender, conn) = http1::handshake(Io(sock)).await.map_err(aborted)?;
+        spawn(async move {
+            let _ = conn.await;
+        });
+        Ok(Sender::Http1(sender))
+    }
+
+    async fn http2(sock: BoxStream) -> io::Result<Self> {
+        let (sender, conn) = http2::handshake(Exec, Io(sock)).await.map_err(aborted)?;
+        spawn(async move {
+            let _ = conn.await;
+        });
+        Ok(Sender::Http2(sender))
+    }
+
+    fn is_http2(&self) -> bool {
+        matches!(self, Sender::Http2(_))
+    }
+
+    fn is_closed(&self) -> bool {
+        match self {
+            Sender::Http1(sender) => sender.is_closed(),
+            Sender::Http2(sender) => sender.is_closed(),
+        }
+    }
+
+    async fn send(
+        &mut self,
+        request: Request<Full<Bytes>>,
+    ) -> io::Result<hyper::Response<hyper::body::Incoming>> {
+        let res = match self {
+            Sender::Http1(sender) => match sender.ready().await {
+                Ok(()) => sender.send_request(request).await,
+                Err(err) => Err(err),
+            },
+            Sender::Http2(sender) => sender.send_request(request).await,
+        };
+        res.map_err(aborted)
+    }
+}
+
+/// Converts a connection error.
+///
+/// These are reported as aborted connections, so that a request on a reused
+/// connection is retried on a new one.
+fn aborted(err: hyper::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, err)
+}
+
+//------------ Exec ----------------------------------------------------------
+
+/// The executor for the tasks of HTTP/2 connections.
+#[derive(Clone, Copy)]
+struct Exec;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "slings-runtime")] {
+        /// A socket of either kind.
+        type BoxStream = Box<dyn Stream>;
+
+        impl<F: Future<Output = ()> + 'static> Executor<F> for Exec {
+            fn execute(&self, fut: F) {
+                spawn(fut)
+            }
+        }
+    }
+    else {
+        /// A socket of either kind.
+        type BoxStream = Box<dyn Stream + Send>;
+
+        impl<F: Future<Output = ()> + Send + 'static> Executor<F> for Exec {
+            fn execute(&self, fut: F) {
+                spawn(fut)
+            }
+        }
+    }
+}
+
+//------------ Io ------------------------------------------------------------
+
+/// A plain or TLS socket.
+trait Stream: AsyncRead + AsyncWrite + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for S {}
+
+/// A socket of the runtime usable by hyper.
+struct Io<S>(S);
+
+impl<S: AsyncRead + Unpin> hyper::rt::Read for Io<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        mut buf: ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut tmp = [0u8; 4096];
+        let len = buf.remaining().min(tmp.len());
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "tokio-runtime")] {
+                let mut read = tokio::io::ReadBuf::new(&mut tmp[..len]);
+                let res = Pin::new(&mut self.0)
+                    .poll_read(cx, &mut read)
+                    .map_ok(|()| read.filled().len());
+            }
+            else {
+                let res = Pin::new(&mut self.0).poll_read(cx, &mut tmp[..len]);
+            }
+        }
+        res.map_ok(|n| buf.put_slice(&tmp[..n]))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> hyper::rt::Write for Io<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "tokio-runtime")] {
+                Pin::new(&mut self.0).poll_shutdown(cx)
+            }
+            else {
+                Pin::new(&mut self.0).poll_close(cx)
+            }
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Coalescing of identical queries.
+//!
+//! While a query for a question is in flight, further queries for the same
+//! question wait for its answer instead of sending their own. The first
+//! query becomes the [`Leader`] and shares its result with all
+//! [`Follower`]s once it is done.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+
+use futures_util::future::poll_fn;
+
+use crate::cache::CacheKey;
+use crate::Answer;
+
+//------------ InFlight ------------------------------------------------------
+
+/// The queries currently in flight.
+#[derive(Default)]
+pub(crate) struct InFlight {
+    queries: Mutex<HashMap<CacheKey, Arc<Slot>>>,
+}
+
+impl InFlight {
+    /// Joins the query for `key`.
+    ///
+    /// If there is no query for `key` in flight yet, the caller becomes its
+    /// leader. Otherwise it becomes a follower of the existing query.
+    pub fn join(&self, key: &CacheKey) -> Join<'_> {
+        let mut queries = self.queries.lock().unwrap();
+        if let Some(slot) = queries.get(key) {
+            return Join::Follower(Follower { slot: slot.clone() });
+        }
+        let slot = Arc::new(Slot::default());
+        queries.insert(key.clone(), slot.clone());
+        Join::Leader(Leader {
+            inflight: self,
+            key: key.clone(),
+            slot,
+        })
+    }
+}
+
+//------------ Join ----------------------------------------------------------
+
+/// The role of a query after joining.
+pub(crate) enum Join<'a> {
+    Leader(Leader<'a>),
+    Follower(Follower),
+}
+
+//------------ Leader --------------------------------------------------------
+
+/// The query that actually sends the question upstream.
+///
+/// Dropping the leader without calling `complete` tells all followers that
+/// they are on their own.
+pub(crate) struct Leader<'a> {
+    inflight: &'a InFlight,
+    key: CacheKey,
+    slot: Arc<Slot>,
+}
+
+impl Leader<'_> {
+    /// Shares the result of the query with all followers.
+    pub fn complete(self, res: &io::Result<Answer>) {
+        let res = match res {
+            Ok(answer) => Ok(answer.clone()),
+            Err(err) => Err((err.kind(), err.to_string())),
+        };
+        self.slot.finish(Some(res));
+    }
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        self.inflight.queries.lock().unwrap().remove(&self.key);
+        self.slot.finish(None);
+    }
+}
+
+//------------ Follower ------------------------------------------------------
+
+/// A query waiting for the result of a leader.
+pub(crate) struct Follower {
+    slot: Arc<Slot>,
+}
+
+impl Follower {
+    /// Waits for the leader to complete.
+    ///
+    /// Returns `None` if the leader was dropped without completing.
+    pub async fn wait(self) -> Option<io::Result<Answer>> {
+        poll_fn(|cx| {
+            let mut state = self.slot.state.lock().unwrap();
+            if !state.done {
+                if !state.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                    state.wakers.push(cx.waker().clone());
+                }
+                return Poll::Pending;
+            }
+            Poll::Ready(state.result.as_ref().map(|res| match res {
+                Ok(answer) => Ok(answer.clone()),
+                Err((kind, msg)) => Err(io::Error::new(*kind, msg.clone())),
+            }))
+        })
+        .await
+    }
+}
+
+//------------ Slot ----------------------------------------------------------
+
+/// The shared state of a query in flight.
+#[derive(Default)]
+struct Slot {
+    state: Mutex<SlotState>,
+}
+
+#[derive(Default)]
+struct SlotState {
+    done: bool,
+    result: Option<Result<Answer, (io::ErrorKind, String)>>,
+    wakers: Vec<Waker>,
+}
+
+impl Slot {
+    /// Stores the result and wakes up all followers.
+    ///
+    /// Only the first call has any effect.
+    fn finish(&self, result: Option<Result<Answer, (io::ErrorKind, String)>>) {
+        let mut state = self.state.lock().unwrap();
+        if state.done {
+            return;
+        }
+        state.done = true;
+        state.result = result;
+        for waker in state.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
@@ -1,20 +1,21 @@
-use std::io;
+use std::future::Future;
 use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::{error, fmt, io};
 
-use domain::base::iana::{Rcode, Rtype};
+use domain::base::iana::{OptRcode, Rcode, Rtype};
 use domain::base::message::Message;
 use domain::base::message_builder::{AdditionalBuilder, MessageBuilder, StreamTarget};
-use domain::base::name::{Name, ToName};
+use domain::base::name::{Name, NameBuilder, PushError, ToName, UncertainName};
+use domain::base::opt::cookie::{ClientCookie, Cookie};
 use domain::base::question::Question;
-use domain::rdata::A;
-use lru_time_cache::LruCache;
-
-const DEFAULT_CACHE_EXPIRE: Duration = Duration::from_secs(10 * 60);
+use domain::rdata::{Aaaa, Ptr, A};
+use futures_util::future::join;
+use futures_util::stream::{FuturesUnordered, StreamExt};
 
 cfg_if::cfg_if! {
     if #[cfg(feature = "slings-runtime")] {
@@ -22,36 +23,122 @@ cfg_if::cfg_if! {
             net::{TcpStream, UdpSocket},
             time::timeout,
         };
-        use futures_util::{AsyncReadExt, AsyncWriteExt};
+        use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+        type StreamReadHalf = Box<dyn AsyncRead + Unpin>;
+        type StreamWriteHalf = Box<dyn AsyncWrite + Unpin>;
+
+        fn spawn<F: Future<Output = ()> + 'static>(future: F) {
+            slings::spawn_local(future).detach();
+        }
+
+        fn split_stream<S>(sock: S) -> (StreamReadHalf, StreamWriteHalf)
+        where
+            S: AsyncRead + AsyncWrite + 'static,
+        {
+            let (reader, writer) = sock.split();
+            (Box::new(reader), Box::new(writer))
+        }
     }
     else if #[cfg(feature = "awak-runtime")] {
         use awak::{
             net::{TcpStream, UdpSocket},
             time::timeout,
         };
-        use futures_util::{AsyncReadExt, AsyncWriteExt};
+        use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+        type StreamReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+        type StreamWriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+        fn spawn<F: Future<Output = ()> + Send + 'static>(future: F) {
+            awak::spawn(future).detach();
+        }
+
+        fn split_stream<S>(sock: S) -> (StreamReadHalf, StreamWriteHalf)
+        where
+            S: AsyncRead + AsyncWrite + Send + 'static,
+        {
+            let (reader, writer) = sock.split();
+            (Box::new(reader), Box::new(writer))
+        }
     }
     else if #[cfg(feature = "tokio-runtime")] {
         use tokio::{
-            io::{AsyncReadExt, AsyncWriteExt},
+            io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
             net::{TcpStream, UdpSocket},
             time::timeout,
         };
+
+        type StreamReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+        type StreamWriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+        fn spawn<F: Future<Output = ()> + Send + 'static>(future: F) {
+            tokio::spawn(future);
+        }
+
+        fn split_stream<S>(sock: S) -> (StreamReadHalf, StreamWriteHalf)
+        where
+            S: AsyncRead + AsyncWrite + Send + 'static,
+        {
+            let (reader, writer) = tokio::io::split(sock);
+            (Box::new(reader), Box::new(writer))
+        }
     }
 }
 
+#[cfg(all(
+    feature = "quic",
+    any(
+        not(feature = "tokio-runtime"),
+        feature = "slings-runtime",
+        feature = "awak-runtime"
+    )
+))]
+compile_error!("the quic feature only works with the tokio runtime");
+
+mod cache;
 mod conf;
-
-pub use conf::{ResolvConf, ResolvOptions};
-use conf::{ServerConf, Transport};
+mod edns;
+#[cfg(feature = "https")]
+mod https;
+mod inflight;
+#[cfg(feature = "quic")]
+mod quic;
+mod stats;
+mod tcp;
+#[cfg(feature = "tls")]
+mod tls;
+
+pub use cache::{Cache, CacheKey, LruCache, NoCache};
+use conf::SearchSuffix;
+pub use conf::{Padding, ResolvConf, ResolvOptions, ServerConf, Transport};
+pub use edns::EdnsOptions;
+#[cfg(feature = "https")]
+use https::HttpsClient;
+use inflight::{InFlight, Join};
+#[cfg(feature = "quic")]
+use quic::QuicClient;
+use stats::{ServerStats, Support};
+use tcp::{Connector, TcpPool};
+#[cfg(feature = "tls")]
+use tls::TlsClient;
 
 const RETRY_RANDOM_PORT: usize = 10;
 
+/// How long to do without case randomization for a server that doesn’t
+/// preserve the case of the query name.
+const CASE_RETRY_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// A stub resolver.
+///
+/// Clones of a resolver share its cache and server state.
+#[derive(Clone)]
 pub struct Resolver {
     preferred: ServerList,
     stream: ServerList,
     options: ResolvOptions,
-    lru_cache: Mutex<LruCache<String, Vec<IpAddr>>>,
+    cache: Arc<dyn Cache>,
+    inflight: Arc<InFlight>,
 }
 
 impl Resolver {
@@ -60,11 +147,20 @@ impl Resolver {
     }
 
     pub fn from_conf(conf: ResolvConf) -> Self {
+        let cache = Arc::new(LruCache::new(&conf.options));
+        Self::with_cache(conf, cache)
+    }
+
+    /// Creates a resolver that keeps its answers in `cache`.
+    ///
+    /// The cache can be shared with other resolvers.
+    pub fn with_cache(conf: ResolvConf, cache: Arc<dyn Cache>) -> Self {
         Resolver {
             preferred: ServerList::from_conf(&conf, |s| s.transport.is_preferred()),
             stream: ServerList::from_conf(&conf, |s| s.transport.is_stream()),
             options: conf.options,
-            lru_cache: Mutex::new(LruCache::with_expiry_duration(DEFAULT_CACHE_EXPIRE)),
+            cache,
+            inflight: Arc::default(),
         }
     }
 
@@ -78,42 +174,312 @@ impl Resolver {
             .await
     }
 
-    fn try_resolve_from_cache(&self, key: &str) -> Option<Vec<IpAddr>> {
-        self.lru_cache.lock().unwrap().get(key).cloned()
+    /// Queries for `question` with the given EDNS settings.
+    ///
+    /// The answer is only taken from or added to the cache if the settings
+    /// don’t go beyond a client subnet.
+    pub async fn query_with_edns<N: ToName, Q: Into<Question<N>>>(
+        &self,
+        question: Q,
+        edns: EdnsOptions,
+    ) -> io::Result<Answer> {
+        Query::new(self)?
+            .with_edns(edns)
+            .run(Query::create_message(question.into()))
+            .await
+    }
+
+    /// Queries for `name` applying the search list.
+    ///
+    /// Relative names are tried with the suffixes of the search list as
+    /// determined by the `ndots`, `default_names`, `dn_search`, and
+    /// `no_tld_query` options. The first answer that is neither NXDOMAIN,
+    /// NODATA, nor SERVFAIL is returned. If there is no such answer, the
+    /// answer for the last name tried is returned.
+    pub async fn search<T: AsRef<str>>(&self, name: T, qtype: Rtype) -> io::Result<Answer> {
+        let mut last = None;
+        for qname in &search_names(name.as_ref(), self.options())? {
+            let answer = self.query((qname, qtype)).await?;
+            if answer.negative().is_none() && answer.header().rcode() != Rcode::SERVFAIL {
+                return Ok(answer);
+            }
+            last = Some(answer);
+        }
+        last.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no names to search"))
+    }
+
+    /// Queries for `question` using the cache regardless of options.
+    async fn lookup<N: ToName, Q: Into<Question<N>>>(&self, question: Q) -> io::Result<Answer> {
+        Query::new(self)?
+            .use_cache(true)
+            .run(Query::create_message(question.into()))
+            .await
+    }
+
+    fn try_resolve_from_cache(&self, key: &CacheKey) -> Option<Answer> {
+        self.cache.get(key)
     }
 
-    fn insert_into_cache(&self, key: &str, val: Vec<IpAddr>) {
-        self.lru_cache.lock().unwrap().insert(key.to_string(), val);
+    /// Adds `answer` to the cache under `key` adjusted for its scope.
+    fn insert_into_cache(&self, key: &CacheKey, answer: Answer) {
+        let scope = answer
+            .opt()
+            .and_then(|opt| opt.opt().client_subnet())
+            .map_or(0, |subnet| subnet.scope_prefix_len());
+        self.cache.insert(key.for_scope(scope), answer);
+    }
+
+    /// Refreshes the cached answer for `key` in the background.
+    ///
+    /// The key is the one of the original query, even if the answer was
+    /// found under its scope zero key, so that the refresh carries the
+    /// client’s subnet.
+    fn prefetch(&self, key: CacheKey) {
+        let resolver = self.clone();
+        spawn(async move {
+            let question = Question::new(key.qname(), key.qtype(), key.qclass());
+            let mut query = match Query::new(&resolver) {
+                Ok(query) => query,
+                Err(_) => return,
+            };
+            if let Some(subnet) = key.client_subnet() {
+                let mut edns = EdnsOptions::new();
+                edns.client_subnet = Some(subnet);
+                query = query.with_edns(edns);
+            }
+            if let Ok(answer) = query.exchange(Query::create_message(question)).await {
+                resolver.insert_into_cache(&key, answer);
+            }
+        });
     }
 
+    /// Looks up the IPv4 and IPv6 addresses of `host`.
+    ///
+    /// If the host doesn’t exist or has no addresses, an error of kind
+    /// `NotFound` is returned that contains a `NegativeAnswer`.
     pub async fn lookup_host<T: AsRef<str>>(&self, host: T) -> io::Result<Vec<IpAddr>> {
-        let host = &host.as_ref();
-        if let Some(v) = self.try_resolve_from_cache(host) {
-            return Ok(v);
+        let mut res = Ok(vec![]);
+        for qname in &search_names(host.as_ref(), self.options())? {
+            res = self.lookup_ips(qname).await;
+            match res {
+                Ok(ref ips) if !ips.is_empty() => break,
+                Err(ref err) if NegativeAnswer::from_error(err).is_none() => break,
+                _ => {}
+            }
+        }
+        res
+    }
+
+    async fn lookup_ips(&self, qname: &Name<Vec<u8>>) -> io::Result<Vec<IpAddr>> {
+        let (first, second) = if self.options().use_inet6 {
+            (Rtype::AAAA, Rtype::A)
+        } else {
+            (Rtype::A, Rtype::AAAA)
+        };
+        let (first, second) = if self.options().single_request {
+            let first = self.query_ips(qname, first).await;
+            (first, self.query_ips(qname, second).await)
+        } else {
+            join(self.query_ips(qname, first), self.query_ips(qname, second)).await
+        };
+        match (first, second) {
+            (Ok(mut first), Ok(second)) => {
+                first.extend(second);
+                Ok(first)
+            }
+            (Ok(ips), Err(_)) | (Err(_), Ok(ips)) => Ok(ips),
+            (Err(err), Err(_)) => Err(err),
+        }
+    }
+
+    async fn query_ips(&self, qname: &Name<Vec<u8>>, rtype: Rtype) -> io::Result<Vec<IpAddr>> {
+        let answer = self.lookup((qname, rtype)).await?;
+        if let Some(negative) = answer.negative() {
+            return Err(negative.into());
+        }
+        let name = answer.canonical_name();
+        let records = || {
+            answer
+                .answer()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+        };
+
+        let mut ips = vec![];
+        for record in records()?.limit_to::<A>().flatten() {
+            if Some(*record.owner()) == name {
+                ips.push(record.data().addr().into());
+            }
+        }
+        for record in records()?.limit_to::<Aaaa>().flatten() {
+            if Some(*record.owner()) == name {
+                ips.push(record.data().addr().into());
+            }
         }
+        Ok(ips)
+    }
 
-        let qname = &Name::<Vec<u8>>::from_str(host)
+    /// Looks up the host names of `addr`.
+    ///
+    /// If the address has no names, an error of kind `NotFound` is returned
+    /// that contains a `NegativeAnswer`.
+    pub async fn lookup_addr(&self, addr: IpAddr) -> io::Result<Vec<Name<Vec<u8>>>> {
+        let qname = reverse_name(addr, self.options().use_ip6dotint)
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
-        let answer = self.query((&qname, Rtype::A)).await?;
+        let answer = self.lookup((&qname, Rtype::PTR)).await?;
+        if let Some(negative) = answer.negative() {
+            return Err(negative.into());
+        }
         let name = answer.canonical_name();
         let records = answer
             .answer()
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
-            .limit_to::<A>();
+            .limit_to::<Ptr<_>>();
 
-        let mut ips = vec![];
+        let mut names = vec![];
         for record in records.flatten() {
             if Some(*record.owner()) == name {
-                ips.push(record.data().addr().into());
+                names.push(record.data().ptrdname().to_name());
             }
         }
-        self.insert_into_cache(host, ips.clone());
-        Ok(ips)
+        Ok(names)
     }
 
     pub async fn query_message(&self, message: QueryMessage) -> io::Result<Answer> {
         Query::new(self)?.run(message).await
     }
+
+    /// Returns how many UDP responses were dropped as not matching.
+    pub fn mismatches(&self) -> Mismatches {
+        let mut res = Mismatches::default();
+        for server in self.preferred.servers.iter().chain(&self.stream.servers) {
+            server.mismatches.add_to(&mut res);
+        }
+        res
+    }
+
+    /// Returns the health of the servers.
+    ///
+    /// The servers used for UDP come first, followed by those used for
+    /// stream transports.
+    pub fn server_health(&self) -> Vec<ServerHealth> {
+        let preferred = self.preferred.servers.iter().map(|s| (s, false));
+        let stream = self.stream.servers.iter().map(|s| (s, true));
+        preferred
+            .chain(stream)
+            .map(|(server, stream)| ServerHealth {
+                addr: server.conf.addr,
+                stream,
+                down: server.stats.is_down(),
+                failures: server.stats.failures(),
+                srtt: server.stats.srtt(),
+                last_success: server.stats.last_success(),
+            })
+            .collect()
+    }
+}
+
+/// Returns the absolute names to query for `name` in order.
+///
+/// This follows glibc’s `res_search()`: names with at least `ndots` dots are
+/// tried as is first, then with each suffix of the search list appended,
+/// and finally as is if that hasn’t happened yet. Names with a trailing dot
+/// are absolute and are only ever tried as is.
+fn search_names(name: &str, options: &ResolvOptions) -> io::Result<Vec<Name<Vec<u8>>>> {
+    let name = match UncertainName::<Vec<u8>>::from_str(name)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+    {
+        UncertainName::Absolute(name) => return Ok(vec![name]),
+        UncertainName::Relative(name) => name,
+    };
+    let dots = name.label_count().saturating_sub(1);
+
+    let mut names = Vec::new();
+    let mut push = |suffix: &SearchSuffix| -> io::Result<()> {
+        if dots == 0 && options.no_tld_query && suffix.is_root() {
+            return Ok(());
+        }
+        let qname = name
+            .clone()
+            .chain(suffix)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .to_name();
+        if !names.contains(&qname) {
+            names.push(qname);
+        }
+        Ok(())
+    };
+    let root = SearchSuffix::root();
+    if dots >= options.ndots {
+        push(&root)?;
+    }
+    if (dots == 0 && options.default_names) || (dots > 0 && options.dn_search) {
+        for suffix in options.search.as_slice() {
+            push(suffix)?;
+        }
+    }
+    push(&root)?;
+    Ok(names)
+}
+
+/// Returns the domain name used for a reverse lookup of `addr`.
+///
+/// IPv6 addresses use the deprecated `ip6.int.` suffix instead of
+/// `ip6.arpa.` if `ip6dotint` is set.
+fn reverse_name(addr: IpAddr, ip6dotint: bool) -> Result<Name<Vec<u8>>, PushError> {
+    match addr {
+        IpAddr::V6(addr) if ip6dotint => {
+            let mut builder = NameBuilder::new_vec();
+            for &item in addr.octets().iter().rev() {
+                builder.append_hex_digit_label(item)?;
+                builder.append_hex_digit_label(item >> 4)?;
+            }
+            builder.append_label(b"ip6")?;
+            builder.append_label(b"int")?;
+            builder.into_name()
+        }
+        _ => Name::reverse_from_addr(addr),
+    }
+}
+
+/// Returns a copy of `message` with the case of its question names
+/// randomized.
+///
+/// Only the header and question section are copied.
+fn randomize_case(message: &QueryMessage) -> QueryMessage {
+    let source = message.as_message();
+    let mut builder = MessageBuilder::from_target(StreamTarget::new_vec()).unwrap();
+    *builder.header_mut() = source.header();
+    let mut builder = builder.question();
+    for question in source.question().flatten() {
+        let mut octets: Vec<u8> = question.qname().to_name::<Vec<u8>>().into_octets();
+        // Label lengths are below 64 and thus never letters.
+        for octet in &mut octets {
+            if octet.is_ascii_alphabetic() && rand::random() {
+                *octet ^= 0x20;
+            }
+        }
+        let qname = Name::from_octets(octets).unwrap();
+        builder
+            .push((qname, question.qtype(), question.qclass()))
+            .unwrap();
+    }
+    builder.additional()
+}
+
+/// Returns whether `answer` repeats the questions of `query` in the exact
+/// same case.
+fn echoes_case(answer: &Message<Vec<u8>>, query: &Message<&[u8]>) -> bool {
+    let mut answered = answer.question();
+    query
+        .question()
+        .all(|question| match (question, answered.next()) {
+            (Ok(question), Some(Ok(answered))) => question
+                .qname()
+                .iter()
+                .map(|label| label.as_slice())
+                .eq(answered.qname().iter().map(|label| label.as_slice())),
+            _ => false,
+        })
 }
 
 impl Default for Resolver {
@@ -128,6 +494,8 @@ pub struct Query<'a> {
     attempt: usize,
     counter: ServerListCounter,
     error: io::Result<Answer>,
+    cache: bool,
+    edns: Option<EdnsOptions>,
 }
 
 impl<'a> Query<'a> {
@@ -139,9 +507,9 @@ impl<'a> Query<'a> {
                     "no servers available",
                 ));
             }
-            (false, resolver.stream.counter(resolver.options().rotate))
+            (false, resolver.stream.counter(resolver.options()))
         } else {
-            (true, resolver.preferred.counter(resolver.options().rotate))
+            (true, resolver.preferred.counter(resolver.options()))
         };
         Ok(Query {
             resolver,
@@ -149,19 +517,114 @@ impl<'a> Query<'a> {
             attempt: 0,
             counter,
             error: Err(io::Error::new(io::ErrorKind::TimedOut, "all timed out")),
+            cache: resolver.options().cache_queries,
+            edns: None,
         })
     }
 
-    pub async fn run(mut self, mut message: QueryMessage) -> io::Result<Answer> {
+    fn use_cache(mut self, cache: bool) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Makes the query use the given EDNS settings.
+    ///
+    /// Unless the settings only concern the client subnet, this also keeps
+    /// the query away from the cache.
+    pub fn with_edns(mut self, edns: EdnsOptions) -> Self {
+        if !edns.is_cacheable() {
+            self.cache = false;
+        }
+        self.edns = Some(edns);
+        self
+    }
+
+    /// Runs the query for `message`.
+    ///
+    /// If the query uses the cache, a cached answer is returned if there is
+    /// one. Otherwise, if a query for the same question is already in
+    /// flight, its answer is shared instead of sending the question again.
+    /// If all servers fail, a stale answer from the cache may be returned.
+    /// Popular cached answers are refreshed in the background shortly
+    /// before they expire.
+    ///
+    /// With a client subnet, answers cached for that subnet and those
+    /// cached for all subnets are used.
+    pub async fn run(self, message: QueryMessage) -> io::Result<Answer> {
+        let resolver = self.resolver;
+        let key = if self.cache {
+            let subnet = self.edns.as_ref().and_then(|edns| edns.client_subnet);
+            message
+                .as_message()
+                .first_question()
+                .map(|q| CacheKey::from(&q).with_client_subnet(subnet))
+        } else {
+            None
+        };
+        let key = match key {
+            Some(key) => key,
+            None => return self.exchange(message).await,
+        };
+        let mut keys = vec![key.for_scope(0)];
+        if key != keys[0] {
+            keys.insert(0, key.clone());
+        }
+        let id = message.header().id();
+        loop {
+            for cached in &keys {
+                if let Some(answer) = resolver.try_resolve_from_cache(cached) {
+                    if resolver.cache.should_prefetch(cached) {
+                        resolver.prefetch(key.clone());
+                    }
+                    return Ok(answer.with_id(id));
+                }
+            }
+            match resolver.inflight.join(&key) {
+                Join::Leader(leader) => {
+                    let mut res = self.exchange(message).await;
+                    match res {
+                        Ok(ref answer) if answer.header().rcode() != Rcode::SERVFAIL => {
+                            resolver.insert_into_cache(&key, answer.clone());
+                        }
+                        _ => {
+                            let stale = keys.iter().find_map(|key| resolver.cache.get_stale(key));
+                            if let Some(answer) = stale {
+                                res = Ok(answer.with_id(id));
+                            }
+                        }
+                    }
+                    leader.complete(&res);
+                    return res;
+                }
+                Join::Follower(follower) => {
+                    if let Some(res) = follower.wait().await {
+                        return res.map(|answer| answer.with_id(id));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends `message` to the servers until one of them gives an answer.
+    ///
+    /// Each attempt uses a new random message ID and, with the
+    /// `randomize_case` option, a new random case of the question. Answers
+    /// are returned with the original ID of `message`. With the `blast`
+    /// option, each attempt goes to all servers at once.
+    async fn exchange(mut self, message: QueryMessage) -> io::Result<Answer> {
+        let id = message.header().id();
+        let blast = self.resolver.options().blast;
         loop {
-            match self.run_query(&mut message).await {
+            let res = if blast {
+                self.blast(&message).await
+            } else {
+                self.query_server(self.current_server(), &message).await
+            };
+            match res.map(|a| a.with_id(id)) {
                 Ok(answer) => {
-                    if answer.header().rcode() == Rcode::FORMERR
-                        && self.current_server().does_edns()
+                    if answer.header().rcode() == Rcode::SERVFAIL
+                        || answer.opt_rcode() == OptRcode::BADCOOKIE
                     {
-                        self.current_server().disable_edns();
-                        continue;
-                    } else if answer.header().rcode() == Rcode::SERVFAIL {
                         self.update_error_servfail(answer);
                     } else if answer.header().tc()
                         && self.preferred
@@ -178,12 +641,105 @@ impl<'a> Query<'a> {
                 }
                 Err(err) => self.update_error(err),
             }
-            if !self.next_server() {
+            let more = if blast {
+                self.next_attempt()
+            } else {
+                self.next_server()
+            };
+            if !more {
                 return self.error;
             }
         }
     }
 
+    /// Sends `message` to all servers of the current list at once.
+    ///
+    /// Returns the first final answer and abandons the other queries. If
+    /// there is no final answer, returns the first answer received or,
+    /// failing that, an error.
+    async fn blast(&self, message: &QueryMessage) -> io::Result<Answer> {
+        let mut queries: FuturesUnordered<_> = self
+            .current_list()
+            .servers
+            .iter()
+            .map(|server| self.query_server(server, message))
+            .collect();
+        let mut answer = None;
+        let mut error = None;
+        while let Some(res) = queries.next().await {
+            match res {
+                Ok(res) if res.is_final() => return Ok(res),
+                Ok(res) => {
+                    answer.get_or_insert(res);
+                }
+                Err(err) if err.kind() != io::ErrorKind::TimedOut => {
+                    error.get_or_insert(err);
+                }
+                Err(_) => {}
+            }
+        }
+        match (answer, error) {
+            (Some(answer), _) => Ok(answer),
+            (None, Some(err)) => Err(err),
+            (None, None) => Err(io::Error::new(io::ErrorKind::TimedOut, "all timed out")),
+        }
+    }
+
+    /// Sends `message` to `server` until it gives an answer.
+    ///
+    /// The query is repeated right away if it turns out the server doesn’t
+    /// support EDNS, or if it has sent a fresh DNS cookie along with a
+    /// BADCOOKIE error. If all answers to a query with randomized case got
+    /// the case wrong, the query is repeated without randomizing it.
+    ///
+    /// As recommended by RFC 6891, a UDP query with EDNS that times out is
+    /// repeated once without EDNS since some middleboxes drop such queries.
+    /// This only happens if the server answered the query before, so that
+    /// servers that are down don’t take twice as long. If the query without
+    /// EDNS gets an answer several times in a row, EDNS is disabled for the
+    /// server for a while.
+    async fn query_server(
+        &self,
+        server: &ServerInfo,
+        message: &QueryMessage,
+    ) -> io::Result<Answer> {
+        let mut cookie_retried = false;
+        let mut edns = true;
+        let mut case = true;
+        loop {
+            match self.run_query(server, message, edns, case).await {
+                Err(err)
+                    if err.kind() == io::ErrorKind::TimedOut
+                        && edns
+                        && server.conf.transport == Transport::Udp
+                        && server.uses_edns(self.edns.as_ref())
+                        && server.stats.failures() == 1 =>
+                {
+                    edns = false;
+                }
+                Ok(answer) if !edns => {
+                    // The server answers without EDNS but not with it.
+                    server.edns.failure();
+                    return Ok(answer);
+                }
+                Ok(answer) if answer.opt_rcode() == OptRcode::BADCOOKIE && !cookie_retried => {
+                    cookie_retried = true;
+                }
+                Ok(answer)
+                    if answer.header().rcode() == Rcode::FORMERR
+                        && server.uses_edns(self.edns.as_ref()) =>
+                {
+                    server.edns.give_up();
+                }
+                Err(err) if CaseMismatch::is_cause_of(&err) => {
+                    server.case.failure();
+                    case = false;
+                }
+                res => return res,
+            }
+        }
+    }
+
     fn create_message(question: Question<impl ToName>) -> QueryMessage {
         let mut message =
             MessageBuilder::from_target(StreamTarget::new_vec()).unwrap();
@@ -193,19 +749,38 @@ impl<'a> Query<'a> {
         message.additional()
     }
 
-    async fn run_query(&mut self, message: &mut QueryMessage) -> io::Result<Answer> {
-        let server = self.current_server();
-        server.prepare_message(message);
-        server.query(message).await
+    /// Sends `message` to `server` once.
+    ///
+    /// EDNS is only used if `edns` is set and the case of the question is
+    /// only randomized if `case` is set, and each only if the server
+    /// supports it.
+    async fn run_query(
+        &self,
+        server: &ServerInfo,
+        message: &QueryMessage,
+        edns: bool,
+        case: bool,
+    ) -> io::Result<Answer> {
+        let exact_case = case && self.resolver.options().randomize_case && server.does_case();
+        let mut message = if exact_case {
+            randomize_case(message)
+        } else {
+            message.clone()
+        };
+        server.prepare_message(&mut message, self.edns.as_ref(), edns)?;
+        server.query(&message, exact_case).await
     }
 
-    fn current_server(&self) -> &ServerInfo {
-        let list = if self.preferred {
+    fn current_list(&self) -> &ServerList {
+        if self.preferred {
             &self.resolver.preferred
         } else {
             &self.resolver.stream
-        };
-        self.counter.info(list)
+        }
+    }
+
+    fn current_server(&self) -> &ServerInfo {
+        self.counter.info(self.current_list())
     }
 
     fn update_error(&mut self, err: io::Error) {
@@ -224,7 +799,7 @@ impl<'a> Query<'a> {
         }
         self.preferred = false;
         self.attempt = 0;
-        self.counter = self.resolver.stream.counter(self.resolver.options().rotate);
+        self.counter = self.resolver.stream.counter(self.resolver.options());
         true
     }
 
@@ -232,16 +807,18 @@ impl<'a> Query<'a> {
         if self.counter.next() {
             return true;
         }
+        self.next_attempt()
+    }
+
+    fn next_attempt(&mut self) -> bool {
         self.attempt += 1;
         if self.attempt >= self.resolver.options().attempts {
             return false;
         }
         self.counter = if self.preferred {
-            self.resolver
-                .preferred
-                .counter(self.resolver.options().rotate)
+            self.resolver.preferred.counter(self.resolver.options())
         } else {
-            self.resolver.stream.counter(self.resolver.options().rotate)
+            self.resolver.stream.counter(self.resolver.options())
         };
         true
     }
@@ -261,6 +838,22 @@ impl Answer {
             && !self.message.header().tc()
     }
 
+    /// Returns what kind of negative answer this is, if any.
+    pub fn negative(&self) -> Option<NegativeAnswer> {
+        match self.message.header().rcode() {
+            Rcode::NXDOMAIN => Some(NegativeAnswer::NxDomain),
+            Rcode::NOERROR if self.message.header_counts().ancount() == 0 => {
+                Some(NegativeAnswer::NoData)
+            }
+            _ => None,
+        }
+    }
+
+    fn with_id(mut self, id: u16) -> Self {
+        self.message.header_mut().set_id(id);
+        self
+    }
+
     pub fn is_truncated(&self) -> bool {
         self.message.header().tc()
     }
@@ -276,81 +869,444 @@ impl From<Message<Vec<u8>>> for Answer {
     }
 }
 
+/// The kind of a negative answer as defined in RFC 2308.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NegativeAnswer {
+    /// The queried name does not exist.
+    NxDomain,
+
+    /// The queried name exists but has no records of the queried type.
+    NoData,
+}
+
+impl NegativeAnswer {
+    /// Returns the negative answer contained in an error, if any.
+    ///
+    /// Errors returned by `Resolver::lookup_host` and `Resolver::lookup_addr`
+    /// for negative answers are of kind `NotFound` and contain the kind of
+    /// negative answer.
+    pub fn from_error(err: &io::Error) -> Option<Self> {
+        err.get_ref()?.downcast_ref().copied()
+    }
+}
+
+impl From<NegativeAnswer> for io::Error {
+    fn from(negative: NegativeAnswer) -> Self {
+        io::Error::new(io::ErrorKind::NotFound, negative)
+    }
+}
+
+impl fmt::Display for NegativeAnswer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NegativeAnswer::NxDomain => f.write_str("no such domain"),
+            NegativeAnswer::NoData => f.write_str("no records of requested type"),
+        }
+    }
+}
+
+impl error::Error for NegativeAnswer {}
+
+/// Numbers of UDP responses dropped because they didn’t match their query.
+///
+/// Unexpected responses may be attempts at spoofing answers.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Mismatches {
+    /// Responses from an address other than the server’s.
+    pub source: u64,
+
+    /// Responses that weren’t DNS responses at all.
+    pub malformed: u64,
+
+    /// Responses with the wrong message ID.
+    pub id: u64,
+
+    /// Responses with the right message ID but the wrong question.
+    pub question: u64,
+
+    /// Responses that didn’t repeat the randomized case of the question.
+    pub case: u64,
+
+    /// Responses with a DNS cookie that wasn’t ours or, once the server
+    /// has sent us a cookie, without one.
+    pub cookie: u64,
+}
+
+impl Mismatches {
+    /// Returns the total number of dropped responses.
+    pub fn total(&self) -> u64 {
+        self.source + self.malformed + self.id + self.question + self.case + self.cookie
+    }
+}
+
+/// The health of a server.
+#[derive(Clone, Debug)]
+pub struct ServerHealth {
+    /// The address of the server.
+    pub addr: SocketAddr,
+
+    /// Whether the server is used with a stream transport.
+    pub stream: bool,
+
+    /// Whether the server has failed too often and is skipped by queries.
+    ///
+    /// Servers that are down are probed in the background with an
+    /// increasing backoff until they answer again.
+    pub down: bool,
+
+    /// The number of failed queries since the last successful one.
+    pub failures: u32,
+
+    /// The smoothed round-trip time of the server’s answers.
+    pub srtt: Duration,
+
+    /// The time of the last answer from the server.
+    pub last_success: Option<Instant>,
+}
+
+/// The counters behind `Mismatches` for a single server.
+#[derive(Debug, Default)]
+struct MismatchCounters {
+    source: AtomicU64,
+    malformed: AtomicU64,
+    id: AtomicU64,
+    question: AtomicU64,
+    case: AtomicU64,
+    cookie: AtomicU64,
+}
+
+impl MismatchCounters {
+    fn add_to(&self, mismatches: &mut Mismatches) {
+        mismatches.source += self.source.load(Ordering::Relaxed);
+        mismatches.malformed += self.malformed.load(Ordering::Relaxed);
+        mismatches.id += self.id.load(Ordering::Relaxed);
+        mismatches.question += self.question.load(Ordering::Relaxed);
+        mismatches.case += self.case.load(Ordering::Relaxed);
+        mismatches.cookie += self.cookie.load(Ordering::Relaxed);
+    }
+}
+
+/// The error for a query whose answers didn’t repeat the case of the question.
+///
+/// This happens with servers that don’t preserve the case of the query
+/// name and makes the query try again without randomizing it.
+#[derive(Debug)]
+struct CaseMismatch;
+
+impl CaseMismatch {
+    fn is_cause_of(err: &io::Error) -> bool {
+        err.get_ref().is_some_and(|err| err.is::<Self>())
+    }
+}
+
+impl From<CaseMismatch> for io::Error {
+    fn from(mismatch: CaseMismatch) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, mismatch)
+    }
+}
+
+impl fmt::Display for CaseMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("answer does not preserve the case of the question")
+    }
+}
+
+impl error::Error for CaseMismatch {}
+
 #[derive(Clone, Debug)]
 struct ServerInfo {
     conf: ServerConf,
-    edns: Arc<AtomicBool>,
+    edns: Arc<Support>,
+    use_edns0: bool,
+    case: Arc<Support>,
+    cookie: Arc<Mutex<Cookie>>,
+    stats: Arc<ServerStats>,
+    mismatches: Arc<MismatchCounters>,
+    tcp: Option<Arc<TcpPool>>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsClient>,
+    #[cfg(feature = "https")]
+    https: Option<Arc<HttpsClient>>,
+    #[cfg(feature = "quic")]
+    quic: Option<Arc<QuicClient>>,
 }
 
 impl ServerInfo {
+    pub fn new(conf: &ServerConf, options: &ResolvOptions) -> Self {
+        ServerInfo {
+            conf: conf.clone(),
+            edns: Arc::new(Support::new(options.edns_retry_interval)),
+            use_edns0: options.use_edns0,
+            case: Arc::new(Support::new(CASE_RETRY_INTERVAL)),
+            cookie: Arc::new(Mutex::new(Cookie::new(ClientCookie::new_random(), None))),
+            stats: Arc::default(),
+            mismatches: Arc::default(),
+            tcp: match conf.transport {
+                Transport::Tcp if options.stay_open => Some(Arc::new(TcpPool::new(
+                    Connector::Tcp(conf.addr),
+                    options.tcp_idle_timeout,
+                ))),
+                #[cfg(feature = "tls")]
+                Transport::Tls if options.stay_open => Some(Arc::new(TcpPool::new(
+                    Connector::Tls(TlsClient::new(conf)),
+                    options.tcp_idle_timeout,
+                ))),
+                _ => None,
+            },
+            #[cfg(feature = "tls")]
+            tls: if conf.transport == Transport::Tls {
+                Some(TlsClient::new(conf))
+            } else {
+                None
+            },
+            #[cfg(feature = "https")]
+            https: match conf.transport {
+                Transport::Https(ref template) => Some(Arc::new(HttpsClient::new(conf, template))),
+                _ => None,
+            },
+            #[cfg(feature = "quic")]
+            quic: if conf.transport == Transport::Quic {
+                Some(Arc::new(QuicClient::new(conf)))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Returns whether queries to the server should use EDNS.
+    ///
+    /// After EDNS failed, it is tried again once the `edns_retry_interval`
+    /// has passed.
     pub fn does_edns(&self) -> bool {
-        self.edns.load(Ordering::Relaxed)
+        self.edns.is_supported()
+    }
+
+    /// Returns whether queries to the server may randomize the name’s case.
+    ///
+    /// Only UDP responses are checked for the case, so there is no point
+    /// in randomizing it for other transports. Servers whose answers got
+    /// the case wrong several queries in a row are queried without it for
+    /// a while.
+    pub fn does_case(&self) -> bool {
+        self.conf.transport == Transport::Udp && self.case.is_supported()
+    }
+
+    /// Returns the DNS cookie to send to the server.
+    ///
+    /// The client cookie is picked at random for each server. The server
+    /// cookie is the one the server sent last.
+    pub fn cookie(&self) -> Cookie {
+        self.cookie.lock().unwrap().clone()
+    }
+
+    /// Remembers the server cookie of `answer` if it is meant for us.
+    pub fn update_cookie(&self, answer: &Answer) {
+        let cookie = match answer.opt().and_then(|opt| opt.opt().cookie()) {
+            Some(cookie) => cookie,
+            None => return,
+        };
+        let mut current = self.cookie.lock().unwrap();
+        if cookie.client() == current.client() && cookie.server().is_some() {
+            *current = cookie;
+        }
+    }
+
+    /// Sends a query to the server in the background to see if it is up.
+    ///
+    /// Any answer counts as a success in the server’s statistics.
+    pub fn probe(&self) {
+        let server = self.clone();
+        spawn(async move {
+            let mut message = Query::create_message(Question::new_in(Name::root_vec(), Rtype::NS));
+            if server.prepare_message(&mut message, None, true).is_ok() {
+                let _ = server.query(&message, false).await;
+            }
+        });
+    }
+
+    /// Returns whether a query with the given EDNS settings uses EDNS.
+    ///
+    /// EDNS is used if it is enabled through the `use_edns0` option or the
+    /// query has its own settings and the server hasn’t failed with it.
+    pub fn uses_edns(&self, edns: Option<&EdnsOptions>) -> bool {
+        (self.use_edns0 || edns.is_some() || self.padding().is_some() || self.tcp.is_some())
+            && self.does_edns()
     }
 
-    pub fn disable_edns(&self) {
-        self.edns.store(false, Ordering::Relaxed);
+    /// Returns the padding policy if queries to the server are padded.
+    fn padding(&self) -> Option<Padding> {
+        match self.conf.padding {
+            Padding::None => None,
+            padding if self.conf.transport.is_encrypted() => Some(padding),
+            _ => None,
+        }
     }
 
-    pub fn prepare_message(&self, query: &mut QueryMessage) {
+    /// Gives `query` a random ID and, if EDNS is used, an OPT record.
+    ///
+    /// EDNS is used if `use_edns` is set and `uses_edns` agrees. Fails with
+    /// `InvalidInput` if the EDNS settings don’t fit into the message.
+    pub fn prepare_message(
+        &self,
+        query: &mut QueryMessage,
+        edns: Option<&EdnsOptions>,
+        use_edns: bool,
+    ) -> io::Result<()> {
         query.rewind();
-        if self.does_edns() {
+        query.header_mut().set_random_id();
+        if use_edns && self.uses_edns(edns) {
             query
                 .opt(|opt| {
                     opt.set_udp_payload_size(self.conf.udp_payload_size);
-                    Ok(())
+                    opt.cookie(self.cookie())?;
+                    if self.tcp.is_some() {
+                        opt.tcp_keepalive(None)?;
+                    }
+                    if let Some(edns) = edns {
+                        edns.apply(opt)?;
+                    }
+                    let len = opt.as_target().as_dgram_slice().len() + 4;
+                    match self.padding().and_then(|padding| padding.padding_len(len)) {
+                        Some(len) => opt.padding(len),
+                        None => Ok(()),
+                    }
                 })
-                .unwrap();
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+        Ok(())
+    }
+
+    /// Sends `query` to the server and waits for the answer.
+    ///
+    /// If `exact_case` is set, a UDP response has to repeat the question
+    /// in the exact case of `query`. If none does before the timeout, the
+    /// query fails with a `CaseMismatch`. The time it takes is recorded in
+    /// the server’s statistics.
+    pub async fn query(&self, query: &QueryMessage, exact_case: bool) -> io::Result<Answer> {
+        let start = Instant::now();
+        let res = self.query_transport(query, exact_case).await;
+        match res {
+            Err(ref err) if !CaseMismatch::is_cause_of(err) => self.stats.failure(start.elapsed()),
+            _ => self.stats.success(start.elapsed()),
         }
+        res
     }
 
-    pub async fn query(&self, query: &QueryMessage) -> io::Result<Answer> {
+    async fn query_transport(&self, query: &QueryMessage, exact_case: bool) -> io::Result<Answer> {
         let res = match self.conf.transport {
             Transport::Udp => {
-                timeout(
+                let case_mismatched = AtomicBool::new(false);
+                let res = timeout(
                     self.conf.request_timeout,
-                    Self::udp_query(query, self.conf.addr, self.conf.recv_size),
+                    Self::udp_query(
+                        query,
+                        self.conf.addr,
+                        self.conf.recv_size,
+                        &self.mismatches,
+                        exact_case.then_some(&case_mismatched),
+                    ),
                 )
-                .await
+                .await;
+                match res {
+                    Err(_) if case_mismatched.load(Ordering::Relaxed) => {
+                        Ok(Err(CaseMismatch.into()))
+                    }
+                    Ok(Ok(_)) if exact_case => {
+                        self.case.success();
+                        res
+                    }
+                    res => res,
+                }
             }
             Transport::Tcp => {
-                timeout(
-                    self.conf.request_timeout,
-                    Self::tcp_query(query, self.conf.addr),
-                )
+                timeout(self.conf.request_timeout, async {
+                    match self.tcp {
+                        Some(ref pool) => pool.query(query).await,
+                        None => Self::tcp_query(query, self.conf.addr).await,
+                    }
+                })
                 .await
             }
-        };
-        match res {
-            Ok(Ok(answer)) => Ok(answer),
+            #[cfg(feature = "tls")]
+            Transport::Tls => {
+                let tls = self.tls.as_ref().expect("TLS server without client");
+                timeout(self.conf.request_timeout, async {
+                    match self.tcp {
+                        Some(ref pool) => pool.query(query).await,
+                        None => tls.query(query).await,
+                    }
+                })
+                .await
+            }
+            #[cfg(feature = "https")]
+            Transport::Https(_) => {
+                let https = self.https.as_ref().expect("HTTPS server without client");
+                timeout(self.conf.request_timeout, https.query(query)).await
+            }
+            #[cfg(feature = "quic")]
+            Transport::Quic => {
+                let quic = self.quic.as_ref().expect("QUIC server without client");
+                timeout(self.conf.request_timeout, quic.query(query)).await
+            }
+        };
+        match res {
+            Ok(Ok(answer)) => {
+                if query.as_message().opt().is_some() {
+                    self.edns.success();
+                }
+                self.update_cookie(&answer);
+                Ok(answer)
+            }
             Ok(Err(err)) => Err(err),
             Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out")),
         }
     }
 
     pub async fn tcp_query(query: &QueryMessage, addr: SocketAddr) -> io::Result<Answer> {
-        let sock = &mut TcpStream::connect(&addr).await?;
+        let mut sock = TcpStream::connect(&addr).await?;
+        Self::stream_query(query, &mut sock).await
+    }
+
+    /// Exchanges `query` over a stream using the length-prefixed framing.
+    pub async fn stream_query<S>(query: &QueryMessage, sock: &mut S) -> io::Result<Answer>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         sock.write_all(query.as_target().as_stream_slice()).await?;
+        sock.flush().await?;
 
         loop {
             let mut len_buf = [0u8; 2];
             sock.read_exact(&mut len_buf).await?;
             let len = u16::from_be_bytes(len_buf) as u64;
             let mut buf = Vec::new();
-            sock.take(len).read_to_end(&mut buf).await?;
+            (&mut *sock).take(len).read_to_end(&mut buf).await?;
             if let Ok(answer) = Message::from_octets(buf) {
                 if answer.is_answer(&query.as_message()) {
                     return Ok(answer.into());
                 }
             } else {
-                return Err(io::Error::new(io::ErrorKind::Other, "short buf"));
+                return Err(io::Error::other("short buf"));
             }
         }
     }
 
+    /// Exchanges `query` with the server at `addr` over UDP.
+    ///
+    /// Only a response from `addr` with the ID and question of `query` is
+    /// accepted. Everything else is dropped and counted in `mismatches`.
+    /// If `query` has a DNS cookie, a response with a different client
+    /// cookie is dropped, too, as is one without a cookie if `query` has
+    /// a server cookie. If `exact_case` is given, a response with the
+    /// question in a different case is dropped and noted in `exact_case`
+    /// so that a server that doesn’t preserve the case can be told apart
+    /// from one that doesn’t answer.
     pub async fn udp_query(
         query: &QueryMessage,
         addr: SocketAddr,
         recv_size: usize,
+        mismatches: &MismatchCounters,
+        exact_case: Option<&AtomicBool>,
     ) -> io::Result<Answer> {
         let sock = Self::udp_bind(addr.is_ipv4()).await?;
         #[cfg(not(feature = "awak-runtime"))]
@@ -359,19 +1315,56 @@ impl ServerInfo {
         sock.connect(addr)?;
         let sent = sock.send(query.as_target().as_dgram_slice()).await?;
         if sent != query.as_target().as_dgram_slice().len() {
-            return Err(io::Error::new(io::ErrorKind::Other, "short UDP send"));
+            return Err(io::Error::other("short UDP send"));
         }
+        let opt = query.as_message().opt();
+        let sent_cookie = opt.as_ref().and_then(|opt| opt.opt().cookie());
+        // Make room for as much as we have advertised.
+        let recv_size = opt.map_or(recv_size, |opt| {
+            recv_size.max(opt.udp_payload_size().into())
+        });
         loop {
             let mut buf = vec![0; recv_size];
-            let len = sock.recv(&mut buf).await?;
+            let (len, peer) = sock.recv_from(&mut buf).await?;
+            if peer != addr {
+                mismatches.source.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
             buf.truncate(len);
             let answer = match Message::from_octets(buf) {
-                Ok(answer) => answer,
-                Err(_) => continue,
+                Ok(answer) if answer.header().qr() => answer,
+                _ => {
+                    mismatches.malformed.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
             };
+            if answer.header().id() != query.header().id() {
+                mismatches.id.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
             if !answer.is_answer(&query.as_message()) {
+                mismatches.question.fetch_add(1, Ordering::Relaxed);
                 continue;
             }
+            if let Some(ref sent) = sent_cookie {
+                let matches = match answer.opt().and_then(|opt| opt.opt().cookie()) {
+                    Some(cookie) => cookie.client() == sent.client(),
+                    // Once the server has sent a cookie, it has to keep
+                    // doing so as per RFC 7873, section 5.3.
+                    None => sent.server().is_none(),
+                };
+                if !matches {
+                    mismatches.cookie.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            }
+            if let Some(mismatched) = exact_case {
+                if !echoes_case(&answer, &query.as_message()) {
+                    mismatches.case.fetch_add(1, Ordering::Relaxed);
+                    mismatched.store(true, Ordering::Relaxed);
+                    continue;
+                }
+            }
             return Ok(answer.into());
         }
     }
@@ -402,21 +1395,6 @@ impl ServerInfo {
     }
 }
 
-impl From<ServerConf> for ServerInfo {
-    fn from(conf: ServerConf) -> Self {
-        ServerInfo {
-            conf,
-            edns: Arc::new(AtomicBool::new(true)),
-        }
-    }
-}
-
-impl<'a> From<&'a ServerConf> for ServerInfo {
-    fn from(conf: &'a ServerConf) -> Self {
-        conf.clone().into()
-    }
-}
-
 #[derive(Clone, Debug)]
 struct ServerList {
     servers: Vec<ServerInfo>,
@@ -433,7 +1411,7 @@ impl ServerList {
                 conf.servers
                     .iter()
                     .filter(|f| filter(f))
-                    .map(Into::into)
+                    .map(|f| ServerInfo::new(f, &conf.options))
                     .collect()
             },
             start: Arc::new(AtomicUsize::new(0)),
@@ -444,12 +1422,40 @@ impl ServerList {
         self.servers.is_empty()
     }
 
-    pub fn counter(&self, rotate: bool) -> ServerListCounter {
+    /// Returns a counter over the servers in the order given by `options`.
+    ///
+    /// With `prefer_fastest`, the servers are ordered by their statistics.
+    /// Otherwise they are used in list order, starting with the next one
+    /// each time if `rotate` is set.
+    ///
+    /// Servers that are down are skipped unless all of them are. Those due
+    /// for a probe are probed in the background.
+    pub fn counter(&self, options: &ResolvOptions) -> ServerListCounter {
+        for server in &self.servers {
+            if server.stats.take_probe() {
+                server.probe();
+            }
+        }
+        let all_down = self.servers.iter().all(|server| server.stats.is_down());
+        let usable = |idx: &usize| all_down || !self.servers[*idx].stats.is_down();
+        if options.prefer_fastest {
+            let order = stats::fastest_first(self.servers.iter().map(|info| &*info.stats));
+            return ServerListCounter::with_order(order.into_iter().filter(usable).collect());
+        }
         let res = ServerListCounter::new(self);
-        if rotate {
+        if options.rotate {
             self.rotate()
         }
-        res
+        if all_down || !self.servers.iter().any(|server| server.stats.is_down()) {
+            return res;
+        }
+        let len = self.servers.len();
+        ServerListCounter::with_order(
+            (res.cur..res.end)
+                .map(|idx| idx % len)
+                .filter(usable)
+                .collect(),
+        )
     }
 
     pub fn iter(&self) -> ServerListIter<'_> {
@@ -482,18 +1488,34 @@ impl Deref for ServerList {
 struct ServerListCounter {
     cur: usize,
     end: usize,
+    /// The indexes of the servers in the order to use them, if not the
+    /// list order.
+    order: Vec<usize>,
 }
 
 impl ServerListCounter {
     fn new(list: &ServerList) -> Self {
         if list.servers.is_empty() {
-            return ServerListCounter { cur: 0, end: 0 };
+            return ServerListCounter {
+                cur: 0,
+                end: 0,
+                order: Vec::new(),
+            };
         }
 
         let start = list.start.load(Ordering::Relaxed) % list.servers.len();
         ServerListCounter {
             cur: start,
             end: start + list.servers.len(),
+            order: Vec::new(),
+        }
+    }
+
+    fn with_order(order: Vec<usize>) -> Self {
+        ServerListCounter {
+            cur: 0,
+            end: order.len(),
+            order,
         }
     }
 
@@ -509,7 +1531,8 @@ impl ServerListCounter {
     }
 
     pub fn info<'a>(&self, list: &'a ServerList) -> &'a ServerInfo {
-        &list[self.cur % list.servers.len()]
+        let idx = self.cur % list.servers.len();
+        &list[self.order.get(idx).copied().unwrap_or(idx)]
     }
 }
 
@@ -553,3 +1576,1616 @@ impl AsRef<Message<Vec<u8>>> for Answer {
         &self.message
     }
 }
+
+//============ Testing ======================================================
+
+#[cfg(all(test, feature = "slings-runtime"))]
+mod test {
+    use super::*;
+    use domain::base::iana::OptionCode;
+    use domain::base::message_builder::AnswerBuilder;
+    use domain::base::opt::cookie::ServerCookie;
+    use domain::base::opt::keepalive::IdleTimeout;
+    use domain::base::opt::subnet::ClientSubnet;
+    use domain::base::opt::UnknownOptData;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Starts a UDP server on localhost that answers with `respond`.
+    fn stub_server<F>(respond: F) -> SocketAddr
+    where
+        F: Fn(&Message<Vec<u8>>) -> Vec<u8> + Send + 'static,
+    {
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        thread::spawn(move || loop {
+            let mut buf = vec![0; 1232];
+            let (len, peer) = sock.recv_from(&mut buf).unwrap();
+            buf.truncate(len);
+            let query = Message::from_octets(buf).unwrap();
+            sock.send_to(&respond(&query), peer).unwrap();
+        });
+        addr
+    }
+
+    /// Starts a TCP server on localhost that answers with `respond`.
+    ///
+    /// Connections are closed after `limit` queries. Also returns the
+    /// number of connections accepted so far.
+    fn tcp_stub_server<F>(limit: usize, respond: F) -> (SocketAddr, Arc<AtomicUsize>)
+    where
+        F: Fn(&Message<Vec<u8>>) -> Vec<u8> + Send + Sync + 'static,
+    {
+        stream_stub_server(limit, |sock| sock, respond)
+    }
+
+    /// Starts a stream server on localhost that wraps connections in `wrap`.
+    fn stream_stub_server<S, W, F>(
+        limit: usize,
+        wrap: W,
+        respond: F,
+    ) -> (SocketAddr, Arc<AtomicUsize>)
+    where
+        S: std::io::Read + std::io::Write + Send + 'static,
+        W: Fn(std::net::TcpStream) -> S + Send + 'static,
+        F: Fn(&Message<Vec<u8>>) -> Vec<u8> + Send + Sync + 'static,
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        let respond = Arc::new(respond);
+        thread::spawn(move || {
+            for sock in listener.incoming() {
+                let mut sock = wrap(sock.unwrap());
+                counter.fetch_add(1, Ordering::SeqCst);
+                let respond = respond.clone();
+                thread::spawn(move || {
+                    for _ in 0..limit {
+                        let mut len = [0u8; 2];
+                        if sock.read_exact(&mut len).is_err() {
+                            return;
+                        }
+                        let mut buf = vec![0; u16::from_be_bytes(len).into()];
+                        sock.read_exact(&mut buf).unwrap();
+                        let answer = respond(&Message::from_octets(buf).unwrap());
+                        sock.write_all(&(answer.len() as u16).to_be_bytes())
+                            .unwrap();
+                        sock.write_all(&answer).unwrap();
+                        sock.flush().unwrap();
+                    }
+                });
+            }
+        });
+        (addr, accepted)
+    }
+
+    fn reply(query: &Message<Vec<u8>>, rcode: Rcode) -> AnswerBuilder<Vec<u8>> {
+        MessageBuilder::new_vec()
+            .start_answer(query, rcode)
+            .unwrap()
+    }
+
+    fn dual_stack(query: &Message<Vec<u8>>) -> Vec<u8> {
+        let question = query.first_question().unwrap();
+        let mut answer = reply(query, Rcode::NOERROR);
+        match question.qtype() {
+            Rtype::A => answer
+                .push((question.qname(), 60, A::new(Ipv4Addr::new(192, 0, 2, 1))))
+                .unwrap(),
+            Rtype::AAAA => answer
+                .push((question.qname(), 60, Aaaa::new(Ipv6Addr::LOCALHOST)))
+                .unwrap(),
+            _ => {}
+        }
+        answer.finish()
+    }
+
+    fn resolver(addr: SocketAddr, options: ResolvOptions) -> Resolver {
+        let mut conf = ResolvConf::new();
+        conf.options = options;
+        conf.servers.push(ServerConf::new(addr, Transport::Udp));
+        conf.finalize();
+        Resolver::from_conf(conf)
+    }
+
+    fn tcp_resolver(addr: SocketAddr) -> Resolver {
+        let mut conf = ResolvConf::new();
+        conf.options.use_vc = true;
+        conf.options.stay_open = true;
+        conf.servers.push(ServerConf::new(addr, Transport::Tcp));
+        conf.finalize();
+        Resolver::from_conf(conf)
+    }
+
+    #[test]
+    fn reverse_names() {
+        let v4 = IpAddr::from(Ipv4Addr::new(192, 0, 2, 1));
+        let v6 = IpAddr::from(Ipv6Addr::LOCALHOST);
+        let v6_name = "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0";
+        assert_eq!(
+            reverse_name(v4, true).unwrap().to_string(),
+            "1.2.0.192.in-addr.arpa"
+        );
+        assert_eq!(
+            reverse_name(v6, false).unwrap().to_string(),
+            format!("{}.ip6.arpa", v6_name)
+        );
+        assert_eq!(
+            reverse_name(v6, true).unwrap().to_string(),
+            format!("{}.ip6.int", v6_name)
+        );
+    }
+
+    #[test]
+    fn lookup_addr_ptr() {
+        let addr = stub_server(|query| {
+            let question = query.first_question().unwrap();
+            if question.qname().to_string().starts_with("2.") {
+                return reply(query, Rcode::NXDOMAIN).finish();
+            }
+            let mut answer = reply(query, Rcode::NOERROR);
+            if question.qtype() == Rtype::PTR && question.qname().to_string().starts_with("1.") {
+                let host = Name::<Vec<u8>>::from_str("host.example.com").unwrap();
+                answer.push((question.qname(), 60, Ptr::new(host))).unwrap();
+            }
+            answer.finish()
+        });
+        let resolver = resolver(addr, ResolvOptions::default());
+        let names = slings::block_on(resolver.lookup_addr(Ipv4Addr::LOCALHOST.into())).unwrap();
+        assert_eq!(names.len(), 1);
+        assert_eq!(names[0].to_string(), "host.example.com");
+
+        for (addr, negative) in [
+            (Ipv4Addr::new(127, 0, 0, 2), NegativeAnswer::NxDomain),
+            (Ipv4Addr::new(127, 0, 0, 3), NegativeAnswer::NoData),
+        ] {
+            let err = slings::block_on(resolver.lookup_addr(addr.into())).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+            assert_eq!(NegativeAnswer::from_error(&err), Some(negative));
+        }
+    }
+
+    fn search_options(search: &[&str]) -> ResolvOptions {
+        let mut options = ResolvOptions::default();
+        for suffix in search {
+            options.search.push(SearchSuffix::from_str(suffix).unwrap());
+        }
+        options.search.push_root();
+        options
+    }
+
+    fn names(name: &str, options: &ResolvOptions) -> Vec<String> {
+        search_names(name, options)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn search_list() {
+        let mut options = search_options(&["svc.cluster.local", "cluster.local"]);
+        options.ndots = 2;
+        assert_eq!(
+            names("web", &options),
+            ["web.svc.cluster.local", "web.cluster.local", "web"]
+        );
+        assert_eq!(
+            names("web.prod", &options),
+            [
+                "web.prod.svc.cluster.local",
+                "web.prod.cluster.local",
+                "web.prod"
+            ]
+        );
+        assert_eq!(
+            names("www.example.com", &options),
+            [
+                "www.example.com",
+                "www.example.com.svc.cluster.local",
+                "www.example.com.cluster.local"
+            ]
+        );
+        assert_eq!(names("web.prod.", &options), ["web.prod"]);
+
+        options.no_tld_query = true;
+        assert_eq!(
+            names("web", &options),
+            ["web.svc.cluster.local", "web.cluster.local"]
+        );
+        options.default_names = false;
+        assert!(names("web", &options).is_empty());
+        options.dn_search = false;
+        assert_eq!(names("web.prod", &options), ["web.prod"]);
+    }
+
+    #[test]
+    fn lookup_host_search() {
+        let addr = stub_server(|query| {
+            let question = query.first_question().unwrap();
+            if question.qname().to_string() != "web.cluster.local" {
+                return reply(query, Rcode::NXDOMAIN).finish();
+            }
+            dual_stack(query)
+        });
+        let resolver = resolver(
+            addr,
+            search_options(&["svc.cluster.local", "cluster.local"]),
+        );
+        let ips = slings::block_on(resolver.lookup_host("web")).unwrap();
+        assert_eq!(ips.len(), 2);
+        let answer = slings::block_on(resolver.search("web", Rtype::A)).unwrap();
+        assert_eq!(answer.header().rcode(), Rcode::NOERROR);
+        let answer = slings::block_on(resolver.search("db", Rtype::A)).unwrap();
+        assert_eq!(answer.header().rcode(), Rcode::NXDOMAIN);
+        let err = slings::block_on(resolver.lookup_host("db")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(
+            NegativeAnswer::from_error(&err),
+            Some(NegativeAnswer::NxDomain)
+        );
+    }
+
+    #[test]
+    fn query_cache() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let addr = stub_server(move |query| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            dual_stack(query)
+        });
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+
+        let resolver = self::resolver(addr, ResolvOptions::default());
+        for _ in 0..2 {
+            slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        let options = ResolvOptions {
+            cache_queries: true,
+            ..Default::default()
+        };
+        let resolver = self::resolver(addr, options);
+        for _ in 0..2 {
+            slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        slings::block_on(resolver.lookup_host("example.com")).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn coalesce_queries() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let addr = stub_server(move |query| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            dual_stack(query)
+        });
+        let resolver = resolver(addr, ResolvOptions::default());
+        let (first, second) = slings::block_on(join(
+            resolver.lookup_host("example.com"),
+            resolver.lookup_host("example.com"),
+        ));
+        assert_eq!(first.unwrap(), second.unwrap());
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn serve_stale() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let addr = stub_server(move |query| {
+            if counter.fetch_add(1, Ordering::SeqCst) > 0 {
+                return Vec::new();
+            }
+            dual_stack(query)
+        });
+        let options = ResolvOptions {
+            timeout: Duration::from_millis(100),
+            attempts: 1,
+            cache_queries: true,
+            cache_max_ttl: Duration::from_millis(1),
+            serve_stale: true,
+            ..Default::default()
+        };
+        let resolver = resolver(addr, options);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        let answer = slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        let record = answer.answer().unwrap().next().unwrap().unwrap();
+        assert_eq!(record.ttl().as_secs(), 30);
+    }
+
+    #[test]
+    fn prefetch() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let addr = stub_server(move |query| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            dual_stack(query)
+        });
+        let options = ResolvOptions {
+            cache_queries: true,
+            cache_max_ttl: Duration::from_millis(200),
+            prefetch: true,
+            prefetch_min_hits: 1,
+            prefetch_ttl_fraction: 0.5,
+            ..Default::default()
+        };
+        let resolver = resolver(addr, options);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        thread::sleep(Duration::from_millis(150));
+        slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        slings::block_on(slings::time::delay_for(Duration::from_millis(50)));
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        thread::sleep(Duration::from_millis(100));
+        slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn stay_open_pipelining() {
+        let (addr, accepted) = tcp_stub_server(usize::MAX, dual_stack);
+        let resolver = tcp_resolver(addr);
+        let ips = slings::block_on(resolver.lookup_host("example.com")).unwrap();
+        assert_eq!(ips.len(), 2);
+        let ips = slings::block_on(resolver.lookup_host("example.org")).unwrap();
+        assert_eq!(ips.len(), 2);
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn stay_open_reconnect() {
+        let (addr, accepted) = tcp_stub_server(1, dual_stack);
+        let resolver = tcp_resolver(addr);
+        for _ in 0..3 {
+            let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+            let answer = slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+            assert_eq!(answer.header_counts().ancount(), 1);
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+    }
+
+    /// Starts a TCP server that counts the connections closed by clients.
+    fn closing_tcp_server() -> (SocketAddr, Arc<AtomicUsize>) {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let closed = Arc::new(AtomicUsize::new(0));
+        let counter = closed.clone();
+        thread::spawn(move || {
+            for sock in listener.incoming() {
+                let mut sock = sock.unwrap();
+                let counter = counter.clone();
+                thread::spawn(move || loop {
+                    let mut len = [0u8; 2];
+                    if sock.read_exact(&mut len).is_err() {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        return;
+                    }
+                    let mut buf = vec![0; u16::from_be_bytes(len).into()];
+                    sock.read_exact(&mut buf).unwrap();
+                    let answer = dual_stack(&Message::from_octets(buf).unwrap());
+                    sock.write_all(&(answer.len() as u16).to_be_bytes())
+                        .unwrap();
+                    sock.write_all(&answer).unwrap();
+                });
+            }
+        });
+        (addr, closed)
+    }
+
+    /// Runs the runtime of this thread until `closed` reaches `count`.
+    fn wait_closed(closed: &AtomicUsize, count: usize) {
+        slings::block_on(async {
+            for _ in 0..100 {
+                if closed.load(Ordering::SeqCst) == count {
+                    break;
+                }
+                slings::time::delay_for(Duration::from_millis(10)).await;
+            }
+        });
+        assert_eq!(closed.load(Ordering::SeqCst), count);
+    }
+
+    #[test]
+    fn stay_open_close() {
+        let (addr, closed) = closing_tcp_server();
+        let resolver = tcp_resolver(addr);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        drop(resolver);
+        wait_closed(&closed, 1);
+    }
+
+    #[test]
+    fn stay_open_idle() {
+        let (addr, closed) = closing_tcp_server();
+        let mut conf = ResolvConf::new();
+        conf.options.use_vc = true;
+        conf.options.stay_open = true;
+        conf.options.tcp_idle_timeout = Duration::from_millis(200);
+        conf.servers.push(ServerConf::new(addr, Transport::Tcp));
+        conf.finalize();
+        let resolver = Resolver::from_conf(conf);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        wait_closed(&closed, 1);
+        drop(resolver);
+    }
+
+    #[test]
+    fn stay_open_close_other_thread() {
+        let (addr, closed) = closing_tcp_server();
+        let mut conf = ResolvConf::new();
+        conf.options.use_vc = true;
+        conf.options.stay_open = true;
+        conf.options.tcp_idle_timeout = Duration::from_millis(200);
+        conf.servers.push(ServerConf::new(addr, Transport::Tcp));
+        conf.finalize();
+        let resolver = Resolver::from_conf(conf);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        let other = resolver.clone();
+        let counter = closed.clone();
+        drop(resolver);
+
+        // The last reference goes away on the other thread.
+        thread::spawn(move || {
+            let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+            slings::block_on(other.query((&qname, Rtype::A))).unwrap();
+            drop(other);
+            wait_closed(&counter, 1);
+        })
+        .join()
+        .unwrap();
+        wait_closed(&closed, 2);
+    }
+
+    #[test]
+    fn stay_open_random_ids() {
+        let ids = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = ids.clone();
+        let (addr, _) = tcp_stub_server(usize::MAX, move |query| {
+            seen.lock().unwrap().push(query.header().id());
+            dual_stack(query)
+        });
+        let resolver = tcp_resolver(addr);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        for _ in 0..4 {
+            slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        }
+        let ids = ids.lock().unwrap();
+        assert_eq!(ids.len(), 4);
+        assert_ne!(*ids, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn stay_open_keepalive() {
+        let (addr, accepted) = tcp_stub_server(usize::MAX, |query| {
+            let keepalive = query.opt().and_then(|opt| opt.opt().tcp_keepalive());
+            assert_eq!(keepalive.unwrap().timeout(), None);
+            let question = query.first_question().unwrap();
+            let mut answer = reply(query, Rcode::NOERROR);
+            answer
+                .push((question.qname(), 60, A::new(Ipv4Addr::new(192, 0, 2, 1))))
+                .unwrap();
+            let mut answer = answer.additional();
+            answer
+                .opt(|opt| opt.tcp_keepalive(Some(IdleTimeout::from(2))))
+                .unwrap();
+            answer.finish()
+        });
+        let resolver = tcp_resolver(addr);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        for _ in 0..2 {
+            slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        thread::sleep(Duration::from_millis(300));
+        slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    /// Starts a DNS-over-TLS server for `name` and returns its CA.
+    ///
+    /// Also returns the number of connections accepted so far.
+    #[cfg(feature = "tls")]
+    fn tls_stub_server<F>(
+        name: &str,
+        respond: F,
+    ) -> (
+        SocketAddr,
+        rustls::pki_types::CertificateDer<'static>,
+        Arc<AtomicUsize>,
+    )
+    where
+        F: Fn(&Message<Vec<u8>>) -> Vec<u8> + Send + Sync + 'static,
+    {
+        let (config, ca) = tls_server_config(name);
+        let config = Arc::new(config);
+        let (addr, accepted) = stream_stub_server(
+            usize::MAX,
+            move |sock| {
+                let conn = rustls::ServerConnection::new(config.clone()).unwrap();
+                rustls::StreamOwned::new(conn, sock)
+            },
+            respond,
+        );
+        (addr, ca, accepted)
+    }
+
+    /// Returns a TLS server configuration for `name` and its CA.
+    #[cfg(feature = "tls")]
+    fn tls_server_config(
+        name: &str,
+    ) -> (
+        rustls::ServerConfig,
+        rustls::pki_types::CertificateDer<'static>,
+    ) {
+        use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+        use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![name.into()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+        let key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key.serialize_der()));
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.der().clone()], key)
+            .unwrap();
+        (config, ca.der().clone())
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls_resolver(
+        addr: SocketAddr,
+        name: &str,
+        ca: rustls::pki_types::CertificateDer<'static>,
+        options: ResolvOptions,
+    ) -> Resolver {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca).unwrap();
+        let mut server = ServerConf::new(addr, Transport::Tls);
+        server.tls_name = Some(name.into());
+        server.tls_config = Some(Arc::new(
+            rustls::ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        let mut conf = ResolvConf::new();
+        conf.options = options;
+        conf.servers.push(server);
+        conf.finalize();
+        Resolver::from_conf(conf)
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn tls_transport() {
+        let (addr, ca, _) = tls_stub_server("dns.example", dual_stack);
+        let resolver = tls_resolver(addr, "dns.example", ca, ResolvOptions::default());
+        let ips = slings::block_on(resolver.lookup_host("example.com")).unwrap();
+        assert_eq!(ips.len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn tls_padding() {
+        let lens = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = lens.clone();
+        let (addr, ca, _) = tls_stub_server("dns.example", move |query| {
+            let padded = query
+                .opt()
+                .unwrap()
+                .opt()
+                .iter::<UnknownOptData<_>>()
+                .any(|option| option.unwrap().code() == OptionCode::PADDING);
+            seen.lock().unwrap().push((query.as_slice().len(), padded));
+            dual_stack(query)
+        });
+        let resolver = tls_resolver(addr, "dns.example", ca, ResolvOptions::default());
+        for name in &["example.com", "a-much-longer-name.example.com"] {
+            let qname = Name::<Vec<u8>>::from_str(name).unwrap();
+            slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        }
+        let lens = lens.lock().unwrap();
+        assert_eq!(lens.len(), 2);
+        assert!(lens.iter().all(|&(len, padded)| padded && len % 128 == 0));
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn tls_stay_open() {
+        let (addr, ca, accepted) = tls_stub_server("dns.example", dual_stack);
+        let options = ResolvOptions {
+            stay_open: true,
+            ..Default::default()
+        };
+        let resolver = tls_resolver(addr, "dns.example", ca, options);
+        for _ in 0..3 {
+            let ips = slings::block_on(resolver.lookup_host("example.com")).unwrap();
+            assert_eq!(ips.len(), 2);
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn tls_wrong_name() {
+        let (addr, ca, _) = tls_stub_server("dns.example", dual_stack);
+        let resolver = tls_resolver(addr, "other.example", ca, ResolvOptions::default());
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        assert!(slings::block_on(resolver.query((&qname, Rtype::A))).is_err());
+    }
+
+    /// Starts an HTTP/1.1 server answering DNS requests with `respond`.
+    ///
+    /// Also returns the number of connections accepted so far.
+    #[cfg(feature = "https")]
+    fn http_stub_server<F>(respond: F) -> (SocketAddr, Arc<AtomicUsize>)
+    where
+        F: Fn(&Message<Vec<u8>>) -> Vec<u8> + Send + Sync + 'static,
+    {
+        use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        use base64::Engine;
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        let respond = Arc::new(respond);
+        thread::spawn(move || {
+            for sock in listener.incoming() {
+                let mut sock = BufReader::new(sock.unwrap());
+                counter.fetch_add(1, Ordering::SeqCst);
+                let respond = respond.clone();
+                thread::spawn(move || loop {
+                    let mut line = String::new();
+                    if sock.read_line(&mut line).unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let target = line.split(' ').nth(1).unwrap().to_string();
+                    let mut len = 0;
+                    loop {
+                        let mut header = String::new();
+                        sock.read_line(&mut header).unwrap();
+                        if header == "\r\n" {
+                            break;
+                        }
+                        let header = header.to_ascii_lowercase();
+                        if let Some(value) = header.strip_prefix("content-length:") {
+                            len = value.trim().parse().unwrap();
+                        }
+                    }
+                    let query = match target.split_once("?dns=") {
+                        Some((_, dns)) => URL_SAFE_NO_PAD.decode(dns).unwrap(),
+                        None => {
+                            let mut body = vec![0; len];
+                            sock.read_exact(&mut body).unwrap();
+                            body
+                        }
+                    };
+                    let answer = respond(&Message::from_octets(query).unwrap());
+                    let sock = sock.get_mut();
+                    write!(
+                        sock,
+                        "HTTP/1.1 200 OK\r\n\
+                         content-type: application/dns-message; charset=utf-8\r\n\
+                         content-length: {}\r\n\r\n",
+                        answer.len()
+                    )
+                    .unwrap();
+                    sock.write_all(&answer).unwrap();
+                });
+            }
+        });
+        (addr, accepted)
+    }
+
+    #[cfg(feature = "https")]
+    fn https_resolver(addr: SocketAddr, template: &str) -> Resolver {
+        let mut conf = ResolvConf::new();
+        conf.servers.push(ServerConf::new(
+            addr,
+            Transport::Https(template.replace("ADDR", &addr.to_string())),
+        ));
+        conf.finalize();
+        Resolver::from_conf(conf)
+    }
+
+    #[test]
+    #[cfg(feature = "https")]
+    fn https_post() {
+        let (addr, accepted) = http_stub_server(dual_stack);
+        let resolver = https_resolver(addr, "http://ADDR/dns-query");
+        let ips = slings::block_on(resolver.lookup_host("example.com")).unwrap();
+        assert_eq!(ips.len(), 2);
+        let connections = accepted.load(Ordering::SeqCst);
+        for _ in 0..3 {
+            let ips = slings::block_on(resolver.lookup_host("example.com")).unwrap();
+            assert_eq!(ips.len(), 2);
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), connections);
+    }
+
+    #[test]
+    #[cfg(feature = "https")]
+    fn https_get() {
+        let (addr, _) = http_stub_server(|query| {
+            assert_eq!(query.header().id(), 0);
+            dual_stack(query)
+        });
+        let resolver = https_resolver(addr, "http://ADDR/dns-query{?dns}");
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        let answer = slings::block_on(resolver.query((&qname, Rtype::AAAA))).unwrap();
+        assert_eq!(answer.header_counts().ancount(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "https")]
+    fn https_too_long() {
+        let (addr, _) = http_stub_server(|_| vec![0; 70000]);
+        let resolver = https_resolver(addr, "http://ADDR/dns-query");
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        match slings::block_on(resolver.query((&qname, Rtype::A))) {
+            Err(err) => assert_eq!(err.to_string(), "response too long"),
+            Ok(_) => panic!("accepted a response that is too long"),
+        }
+    }
+
+    /// Starts a DNS-over-HTTPS server for `name` that only speaks HTTP/2.
+    ///
+    /// Also returns its CA and the URIs requested so far.
+    #[cfg(feature = "https")]
+    fn h2_stub_server<F>(
+        name: &str,
+        respond: F,
+    ) -> (
+        SocketAddr,
+        rustls::pki_types::CertificateDer<'static>,
+        Arc<std::sync::Mutex<Vec<String>>>,
+    )
+    where
+        F: Fn(&Message<Vec<u8>>) -> Vec<u8> + Send + Sync + 'static,
+    {
+        use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        use base64::Engine;
+
+        let (mut config, ca) = tls_server_config(name);
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let uris = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = uris.clone();
+        let respond = Arc::new(respond);
+        let handle = move |request: http::Request<h2::RecvStream>,
+                           mut sender: h2::server::SendResponse<_>| {
+            seen.lock().unwrap().push(request.uri().to_string());
+            let respond = respond.clone();
+            async move {
+                let dns = request.uri().query().and_then(|q| q.strip_prefix("dns="));
+                let query = match dns {
+                    Some(dns) => URL_SAFE_NO_PAD.decode(dns).unwrap(),
+                    None => {
+                        let mut body = request.into_body();
+                        let mut query = Vec::new();
+                        while let Some(data) = body.data().await {
+                            let data = data.unwrap();
+                            body.flow_control().release_capacity(data.len()).unwrap();
+                            query.extend_from_slice(&data);
+                        }
+                        query
+                    }
+                };
+                let answer = respond(&Message::from_octets(query).unwrap());
+                let response = http::Response::builder()
+                    .header("content-type", "application/dns-message")
+                    .body(())
+                    .unwrap();
+                let mut stream = sender.send_response(response, false).unwrap();
+                stream.send_data(answer.into(), true).unwrap();
+            }
+        };
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_io()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                loop {
+                    let (sock, _) = listener.accept().await.unwrap();
+                    let acceptor = acceptor.clone();
+                    let handle = handle.clone();
+                    tokio::spawn(async move {
+                        let sock = acceptor.accept(sock).await.unwrap();
+                        assert_eq!(sock.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+                        let mut conn = h2::server::handshake(sock).await.unwrap();
+                        while let Some(request) = conn.accept().await {
+                            let (request, sender) = request.unwrap();
+                            tokio::spawn(handle(request, sender));
+                        }
+                    });
+                }
+            });
+        });
+        (addr, ca, uris)
+    }
+
+    #[test]
+    #[cfg(feature = "https")]
+    fn https_http2() {
+        let (addr, ca, uris) = h2_stub_server("dns.example", dual_stack);
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca).unwrap();
+        let tls_config = Arc::new(
+            rustls::ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        );
+        for template in &[
+            "https://dns.example/dns-query",
+            "https://dns.example/dns-query{?dns}",
+        ] {
+            let mut server = ServerConf::new(addr, Transport::Https(template.to_string()));
+            server.tls_config = Some(tls_config.clone());
+            let mut conf = ResolvConf::new();
+            conf.servers.push(server);
+            conf.finalize();
+            let resolver = Resolver::from_conf(conf);
+            let ips = slings::block_on(resolver.lookup_host("example.com")).unwrap();
+            assert_eq!(ips.len(), 2);
+        }
+        let uris = uris.lock().unwrap();
+        assert_eq!(uris.len(), 4);
+        assert!(uris[..2]
+            .iter()
+            .all(|uri| uri == "https://dns.example/dns-query"));
+        assert!(uris[2..]
+            .iter()
+            .all(|uri| uri.starts_with("https://dns.example/dns-query?dns=")));
+    }
+
+    /// Starts an HTTP proxy that only supports `CONNECT`.
+    ///
+    /// Also returns the targets of the tunnels requested so far.
+    #[cfg(feature = "https")]
+    fn connect_proxy() -> (SocketAddr, Arc<std::sync::Mutex<Vec<String>>>) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let targets = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = targets.clone();
+        thread::spawn(move || {
+            for sock in listener.incoming() {
+                let mut client = BufReader::new(sock.unwrap());
+                let mut line = String::new();
+                client.read_line(&mut line).unwrap();
+                let target = line.split(' ').nth(1).unwrap().to_string();
+                loop {
+                    let mut header = String::new();
+                    client.read_line(&mut header).unwrap();
+                    if header == "\r\n" {
+                        break;
+                    }
+                }
+                seen.lock().unwrap().push(target.clone());
+                let mut client = client.into_inner();
+                let mut server = std::net::TcpStream::connect(target).unwrap();
+                client
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .unwrap();
+                let (mut client_rx, mut server_tx) =
+                    (client.try_clone().unwrap(), server.try_clone().unwrap());
+                thread::spawn(move || std::io::copy(&mut client_rx, &mut server_tx));
+                thread::spawn(move || std::io::copy(&mut server, &mut client));
+            }
+        });
+        (addr, targets)
+    }
+
+    #[test]
+    #[cfg(feature = "https")]
+    fn https_proxy() {
+        let (addr, _) = http_stub_server(dual_stack);
+        let (proxy, targets) = connect_proxy();
+        let mut server =
+            ServerConf::new(addr, Transport::Https(format!("http://{}/dns-query", addr)));
+        server.https_proxy = Some(proxy);
+        let mut conf = ResolvConf::new();
+        conf.servers.push(server);
+        conf.finalize();
+        let resolver = Resolver::from_conf(conf);
+        let ips = slings::block_on(resolver.lookup_host("example.com")).unwrap();
+        assert_eq!(ips.len(), 2);
+        let targets = targets.lock().unwrap();
+        assert!(!targets.is_empty());
+        assert!(targets.iter().all(|target| *target == addr.to_string()));
+    }
+
+    #[test]
+    fn spoofed_responses() {
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        let ids = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = ids.clone();
+        thread::spawn(move || loop {
+            let mut buf = vec![0; 1232];
+            let (len, peer) = sock.recv_from(&mut buf).unwrap();
+            buf.truncate(len);
+            let query = Message::from_octets(buf).unwrap();
+            seen.lock().unwrap().push(query.header().id());
+
+            let mut wrong_id = dual_stack(&query);
+            wrong_id[1] ^= 1;
+            sock.send_to(&wrong_id, peer).unwrap();
+            let mut other = MessageBuilder::new_vec();
+            other.header_mut().set_id(query.header().id());
+            let mut other = other.question();
+            other.push((Name::root_vec(), Rtype::A)).unwrap();
+            let other = other.into_message();
+            sock.send_to(&dual_stack(&other), peer).unwrap();
+            sock.send_to(b"junk", peer).unwrap();
+            sock.send_to(&dual_stack(&query), peer).unwrap();
+        });
+        let resolver = resolver(addr, ResolvOptions::default());
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        for _ in 0..3 {
+            let answer = slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+            assert_eq!(answer.header().id(), 0);
+            assert_eq!(answer.header_counts().ancount(), 1);
+        }
+        assert_eq!(
+            resolver.mismatches(),
+            Mismatches {
+                source: 0,
+                malformed: 3,
+                id: 3,
+                question: 3,
+                case: 0,
+                cookie: 0,
+            }
+        );
+        let ids = ids.lock().unwrap();
+        assert!(ids.iter().any(|id| *id != ids[0]));
+    }
+
+    #[test]
+    fn randomized_case() {
+        let qnames = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = qnames.clone();
+        let addr = stub_server(move |query| {
+            let qname = query.first_question().unwrap().qname().to_string();
+            seen.lock().unwrap().push(qname);
+            dual_stack(query)
+        });
+        let options = ResolvOptions {
+            randomize_case: true,
+            ..Default::default()
+        };
+        let resolver = resolver(addr, options);
+        let qname = "case-randomization.example.com";
+        for _ in 0..3 {
+            let answer = slings::block_on(
+                resolver.query((Name::<Vec<u8>>::from_str(qname).unwrap(), Rtype::A)),
+            )
+            .unwrap();
+            assert_eq!(answer.header_counts().ancount(), 1);
+        }
+        assert_eq!(resolver.mismatches().total(), 0);
+        let qnames = qnames.lock().unwrap();
+        assert_eq!(qnames.len(), 3);
+        assert!(qnames.iter().all(|name| name.eq_ignore_ascii_case(qname)));
+        assert!(qnames.iter().any(|name| name != qname));
+    }
+
+    #[test]
+    fn case_normalizing_server() {
+        let qnames = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = qnames.clone();
+        let addr = stub_server(move |query| {
+            let qname = query.first_question().unwrap().qname().to_string();
+            seen.lock().unwrap().push(qname);
+            let mut answer = dual_stack(query);
+            let mut pos = 12;
+            while answer[pos] != 0 {
+                let end = pos + 1 + usize::from(answer[pos]);
+                answer[pos + 1..end].make_ascii_lowercase();
+                pos = end;
+            }
+            answer
+        });
+        let options = ResolvOptions {
+            randomize_case: true,
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let resolver = resolver(addr, options);
+        let qname = "case-randomization.example.com";
+        for _ in 0..4 {
+            let answer = slings::block_on(
+                resolver.query((Name::<Vec<u8>>::from_str(qname).unwrap(), Rtype::A)),
+            )
+            .unwrap();
+            assert_eq!(answer.header_counts().ancount(), 1);
+        }
+        assert_eq!(resolver.mismatches().case, 3);
+        assert!(!resolver.preferred[0].does_case());
+        let qnames = qnames.lock().unwrap();
+        assert_eq!(qnames.len(), 7);
+        for pair in qnames[..6].chunks(2) {
+            assert_ne!(pair[0], qname);
+            assert_eq!(pair[1], qname);
+        }
+        assert_eq!(qnames[6], qname);
+    }
+
+    #[test]
+    fn spoofed_case() {
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        thread::spawn(move || loop {
+            let mut buf = vec![0; 1232];
+            let (len, peer) = sock.recv_from(&mut buf).unwrap();
+            buf.truncate(len);
+            let query = Message::from_octets(buf).unwrap();
+            let mut spoofed = dual_stack(&query);
+            spoofed[13..].make_ascii_lowercase();
+            sock.send_to(&spoofed, peer).unwrap();
+            sock.send_to(&dual_stack(&query), peer).unwrap();
+        });
+        let options = ResolvOptions {
+            randomize_case: true,
+            ..Default::default()
+        };
+        let resolver = resolver(addr, options);
+        let qname = Name::<Vec<u8>>::from_str("case-randomization.example.com").unwrap();
+        for _ in 0..4 {
+            let answer = slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+            assert_eq!(answer.header_counts().ancount(), 1);
+        }
+        assert_eq!(resolver.mismatches().case, 4);
+        assert!(resolver.preferred[0].does_case());
+    }
+
+    #[test]
+    fn cookies() {
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        let cookies = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = cookies.clone();
+        thread::spawn(move || loop {
+            let mut buf = vec![0; 1232];
+            let (len, peer) = sock.recv_from(&mut buf).unwrap();
+            buf.truncate(len);
+            let query = Message::from_octets(buf).unwrap();
+            let cookie = query.opt().and_then(|opt| opt.opt().cookie()).unwrap();
+            seen.lock().unwrap().push(cookie.clone());
+
+            let server = ServerCookie::from_octets(b"servcook");
+            let respond = |rcode: OptRcode, cookie: Cookie| {
+                let mut answer = reply(&query, rcode.rcode()).additional();
+                answer
+                    .opt(|opt| {
+                        opt.set_rcode(rcode);
+                        opt.cookie(cookie)
+                    })
+                    .unwrap();
+                answer.finish()
+            };
+            let fresh = Cookie::new(cookie.client(), Some(server.clone()));
+            if cookie.server() != Some(&server) {
+                sock.send_to(&respond(OptRcode::BADCOOKIE, fresh), peer)
+                    .unwrap();
+                continue;
+            }
+            let spoofed = Cookie::new(ClientCookie::new_random(), Some(server));
+            sock.send_to(&respond(OptRcode::NOERROR, spoofed), peer)
+                .unwrap();
+            let without_cookie = reply(&query, Rcode::NOERROR).finish();
+            sock.send_to(&without_cookie, peer).unwrap();
+            sock.send_to(&respond(OptRcode::NOERROR, fresh), peer)
+                .unwrap();
+        });
+        let options = ResolvOptions {
+            use_edns0: true,
+            ..Default::default()
+        };
+        let resolver = resolver(addr, options);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        for _ in 0..2 {
+            let answer = slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+            assert_eq!(answer.opt_rcode(), OptRcode::NOERROR);
+        }
+        assert_eq!(resolver.mismatches().cookie, 4);
+        let cookies = cookies.lock().unwrap();
+        assert_eq!(cookies.len(), 3);
+        assert!(cookies[0].server().is_none());
+        assert!(cookies[1..].iter().all(|cookie| cookie.server().is_some()));
+        assert!(cookies
+            .iter()
+            .all(|cookie| cookie.client() == cookies[0].client()));
+    }
+
+    #[test]
+    fn blast() {
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        thread::spawn(move || loop {
+            let mut buf = vec![0; 1232];
+            silent.recv_from(&mut buf).unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let addr = stub_server(dual_stack);
+
+        let mut conf = ResolvConf::new();
+        conf.options.blast = true;
+        conf.options.timeout = Duration::from_secs(10);
+        conf.servers
+            .push(ServerConf::new(silent_addr, Transport::Udp));
+        conf.servers.push(ServerConf::new(addr, Transport::Udp));
+        conf.finalize();
+        let resolver = Resolver::from_conf(conf);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        let start = std::time::Instant::now();
+        let answer = slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        assert!(answer.is_final());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        while received.load(Ordering::SeqCst) == 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn prefer_fastest() {
+        let slow_count = Arc::new(AtomicUsize::new(0));
+        let counter = slow_count.clone();
+        let slow = stub_server(move |query| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            dual_stack(query)
+        });
+        let fast_count = Arc::new(AtomicUsize::new(0));
+        let counter = fast_count.clone();
+        let fast = stub_server(move |query| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            dual_stack(query)
+        });
+
+        let mut conf = ResolvConf::new();
+        conf.options.prefer_fastest = true;
+        conf.servers.push(ServerConf::new(slow, Transport::Udp));
+        conf.servers.push(ServerConf::new(fast, Transport::Udp));
+        conf.finalize();
+        let resolver = Resolver::from_conf(conf);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        for _ in 0..20 {
+            slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        }
+        assert!(slow_count.load(Ordering::SeqCst) < 10);
+        assert!(fast_count.load(Ordering::SeqCst) > 10);
+    }
+
+    #[test]
+    fn down_server() {
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let flaky = sock.local_addr().unwrap();
+        let up = Arc::new(AtomicBool::new(false));
+        let received = Arc::new(AtomicUsize::new(0));
+        let (is_up, counter) = (up.clone(), received.clone());
+        thread::spawn(move || loop {
+            let mut buf = vec![0; 1232];
+            let (len, peer) = sock.recv_from(&mut buf).unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            if is_up.load(Ordering::SeqCst) {
+                buf.truncate(len);
+                let query = Message::from_octets(buf).unwrap();
+                sock.send_to(&dual_stack(&query), peer).unwrap();
+            }
+        });
+        let addr = stub_server(dual_stack);
+
+        let mut conf = ResolvConf::new();
+        conf.options.timeout = Duration::from_millis(200);
+        conf.servers.push(ServerConf::new(flaky, Transport::Udp));
+        conf.servers.push(ServerConf::new(addr, Transport::Udp));
+        conf.finalize();
+        let resolver = Resolver::from_conf(conf);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        while !resolver.server_health()[0].down {
+            slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        }
+        let count = received.load(Ordering::SeqCst);
+
+        // The server is skipped while down.
+        let start = std::time::Instant::now();
+        slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert_eq!(received.load(Ordering::SeqCst), count);
+
+        // After the backoff, a probe finds it up again.
+        up.store(true, Ordering::SeqCst);
+        while resolver.server_health()[0].down {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(100));
+            slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+            slings::block_on(slings::time::delay_for(Duration::from_millis(50)));
+        }
+        assert_eq!(received.load(Ordering::SeqCst), count + 1);
+        assert!(resolver.server_health()[0].last_success.is_some());
+        slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        assert_eq!(received.load(Ordering::SeqCst), count + 2);
+    }
+
+    #[test]
+    fn edns_retry() {
+        let with_opt = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = with_opt.clone();
+        let addr = stub_server(move |query| {
+            seen.lock().unwrap().push(query.opt().is_some());
+            if query.opt().is_some() {
+                reply(query, Rcode::FORMERR).finish()
+            } else {
+                dual_stack(query)
+            }
+        });
+        let options = ResolvOptions {
+            use_edns0: true,
+            edns_retry_interval: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let resolver = resolver(addr, options);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        thread::sleep(Duration::from_millis(150));
+        slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        assert_eq!(*with_opt.lock().unwrap(), [true, false, false, true, false]);
+    }
+
+    #[test]
+    fn edns_timeout_fallback() {
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        let with_opt = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = with_opt.clone();
+        thread::spawn(move || loop {
+            let mut buf = vec![0; 1232];
+            let (len, peer) = sock.recv_from(&mut buf).unwrap();
+            buf.truncate(len);
+            let query = Message::from_octets(buf).unwrap();
+            seen.lock().unwrap().push(query.opt().is_some());
+            if query.opt().is_none() {
+                sock.send_to(&dual_stack(&query), peer).unwrap();
+            }
+        });
+        let options = ResolvOptions {
+            timeout: Duration::from_millis(100),
+            use_edns0: true,
+            ..Default::default()
+        };
+        let resolver = resolver(addr, options);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        for _ in 0..4 {
+            slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        }
+        assert_eq!(
+            *with_opt.lock().unwrap(),
+            [true, false, true, false, true, false, false]
+        );
+    }
+
+    #[test]
+    fn edns_lost_query() {
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        let with_opt = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = with_opt.clone();
+        thread::spawn(move || {
+            for i in 0.. {
+                let mut buf = vec![0; 1232];
+                let (len, peer) = sock.recv_from(&mut buf).unwrap();
+                buf.truncate(len);
+                let query = Message::from_octets(buf).unwrap();
+                seen.lock().unwrap().push(query.opt().is_some());
+                if i != 0 {
+                    sock.send_to(&dual_stack(&query), peer).unwrap();
+                }
+            }
+        });
+        let options = ResolvOptions {
+            timeout: Duration::from_millis(100),
+            use_edns0: true,
+            ..Default::default()
+        };
+        let resolver = resolver(addr, options);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        for _ in 0..3 {
+            slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        }
+        assert_eq!(*with_opt.lock().unwrap(), [true, false, true, true]);
+    }
+
+    #[test]
+    fn edns_dead_server() {
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        let with_opt = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = with_opt.clone();
+        thread::spawn(move || loop {
+            let mut buf = vec![0; 1232];
+            let (len, _) = sock.recv_from(&mut buf).unwrap();
+            buf.truncate(len);
+            let query = Message::from_octets(buf).unwrap();
+            seen.lock().unwrap().push(query.opt().is_some());
+        });
+        let options = ResolvOptions {
+            timeout: Duration::from_millis(100),
+            use_edns0: true,
+            attempts: 3,
+            ..Default::default()
+        };
+        let resolver = resolver(addr, options);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        assert!(slings::block_on(resolver.query((&qname, Rtype::A))).is_err());
+        assert_eq!(*with_opt.lock().unwrap(), [true, false, true, true]);
+        assert!(resolver.preferred[0].does_edns());
+    }
+
+    #[test]
+    fn default_edns() {
+        let payload_sizes = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = payload_sizes.clone();
+        let addr = stub_server(move |query| {
+            let opt = query.opt().map(|opt| opt.udp_payload_size());
+            seen.lock().unwrap().push(opt);
+            dual_stack(query)
+        });
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        let resolver = resolver(addr, ResolvOptions::default());
+        slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+
+        let mut conf = ResolvConf::new();
+        conf.parse(&mut &b"options edns0\n"[..]).unwrap();
+        conf.servers.push(ServerConf::new(addr, Transport::Udp));
+        conf.finalize();
+        let resolver = Resolver::from_conf(conf);
+        slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+        assert_eq!(*payload_sizes.lock().unwrap(), [None, Some(1232)]);
+    }
+
+    #[test]
+    fn edns_options() {
+        let opts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = opts.clone();
+        let addr = stub_server(move |query| {
+            let opt = query.opt().map(|opt| {
+                let options: Vec<_> = opt
+                    .opt()
+                    .iter::<UnknownOptData<_>>()
+                    .map(|option| {
+                        let option = option.unwrap();
+                        (option.code(), option.as_slice().to_vec())
+                    })
+                    .collect();
+                (opt.dnssec_ok(), opt.udp_payload_size(), options)
+            });
+            seen.lock().unwrap().push(opt);
+            dual_stack(query)
+        });
+        let options = ResolvOptions {
+            cache_queries: true,
+            ..Default::default()
+        };
+        let resolver = resolver(addr, options);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+
+        let mut edns = EdnsOptions::new();
+        edns.dnssec_ok = true;
+        edns.udp_payload_size = Some(4096);
+        edns.add_option(OptionCode::NSID, Vec::new()).unwrap();
+        assert!(edns.add_option(OptionCode::NSID, vec![0; 0x10000]).is_err());
+        for _ in 0..2 {
+            slings::block_on(resolver.query_with_edns((&qname, Rtype::A), edns.clone())).unwrap();
+        }
+
+        let opts = opts.lock().unwrap();
+        assert_eq!(opts.len(), 3);
+        assert!(opts[0].is_none());
+        let (dnssec_ok, payload_size, ref options) = *opts[2].as_ref().unwrap();
+        assert!(dnssec_ok);
+        assert_eq!(payload_size, 4096);
+        assert!(options.contains(&(OptionCode::NSID, Vec::new())));
+        assert!(options.iter().any(|(code, _)| *code == OptionCode::COOKIE));
+    }
+
+    #[test]
+    fn edns_options_too_long() {
+        let addr = stub_server(dual_stack);
+        let resolver = resolver(addr, ResolvOptions::default());
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        let mut edns = EdnsOptions::new();
+        for _ in 0..2 {
+            edns.add_option(OptionCode::NSID, vec![0; 40000]).unwrap();
+        }
+        match slings::block_on(resolver.query_with_edns((&qname, Rtype::A), edns)) {
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("query with too long options succeeded"),
+        }
+    }
+
+    #[test]
+    fn client_subnet() {
+        let subnets = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = subnets.clone();
+        let addr = stub_server(move |query| {
+            let question = query.first_question().unwrap();
+            let subnet = query
+                .opt()
+                .and_then(|opt| opt.opt().client_subnet())
+                .unwrap();
+            seen.lock().unwrap().push(subnet);
+            let scope = if question.qname().to_string() == "local.example.com" {
+                24
+            } else {
+                0
+            };
+            let mut answer = reply(query, Rcode::NOERROR);
+            answer
+                .push((question.qname(), 60, A::new(Ipv4Addr::new(192, 0, 2, 1))))
+                .unwrap();
+            let mut answer = answer.additional();
+            answer
+                .opt(|opt| opt.client_subnet(subnet.source_prefix_len(), scope, subnet.addr()))
+                .unwrap();
+            answer.finish()
+        });
+        let options = ResolvOptions {
+            cache_queries: true,
+            ..Default::default()
+        };
+        let resolver = resolver(addr, options);
+        let query = |name: &str, client: [u8; 4]| {
+            let qname = Name::<Vec<u8>>::from_str(name).unwrap();
+            let mut edns = EdnsOptions::new();
+            edns.client_subnet = Some(ClientSubnet::new(24, 0, Ipv4Addr::from(client).into()));
+            slings::block_on(resolver.query_with_edns((&qname, Rtype::A), edns)).unwrap();
+        };
+        for name in &["global.example.com", "local.example.com"] {
+            query(name, [198, 51, 100, 7]);
+            query(name, [198, 51, 100, 8]);
+            query(name, [203, 0, 113, 7]);
+        }
+
+        let subnets = subnets.lock().unwrap();
+        assert_eq!(subnets.len(), 3);
+        let expected = ClientSubnet::new(24, 0, Ipv4Addr::new(198, 51, 100, 0).into());
+        assert_eq!(subnets[0], expected);
+        assert_eq!(subnets[1], expected);
+        assert_eq!(
+            subnets[2].addr(),
+            IpAddr::from(Ipv4Addr::new(203, 0, 113, 0))
+        );
+    }
+
+    #[test]
+    fn client_subnet_prefetch() {
+        let subnets = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = subnets.clone();
+        let addr = stub_server(move |query| {
+            let question = query.first_question().unwrap();
+            let subnet = query
+                .opt()
+                .and_then(|opt| opt.opt().client_subnet())
+                .unwrap();
+            seen.lock().unwrap().push(subnet);
+            let mut answer = reply(query, Rcode::NOERROR);
+            answer
+                .push((question.qname(), 60, A::new(Ipv4Addr::new(192, 0, 2, 1))))
+                .unwrap();
+            let mut answer = answer.additional();
+            answer
+                .opt(|opt| opt.client_subnet(subnet.source_prefix_len(), 0, subnet.addr()))
+                .unwrap();
+            answer.finish()
+        });
+        let options = ResolvOptions {
+            cache_queries: true,
+            cache_max_ttl: Duration::from_millis(200),
+            prefetch: true,
+            prefetch_min_hits: 1,
+            prefetch_ttl_fraction: 0.5,
+            ..Default::default()
+        };
+        let resolver = resolver(addr, options);
+        let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+        let query = || {
+            let mut edns = EdnsOptions::new();
+            edns.client_subnet = Some(ClientSubnet::new(
+                24,
+                0,
+                Ipv4Addr::new(198, 51, 100, 7).into(),
+            ));
+            slings::block_on(resolver.query_with_edns((&qname, Rtype::A), edns)).unwrap();
+        };
+        query();
+        thread::sleep(Duration::from_millis(150));
+        query();
+        slings::block_on(slings::time::delay_for(Duration::from_millis(50)));
+
+        let subnets = subnets.lock().unwrap();
+        assert_eq!(subnets.len(), 2);
+        let expected = ClientSubnet::new(24, 0, Ipv4Addr::new(198, 51, 100, 0).into());
+        assert_eq!(subnets[1], expected);
+    }
+
+    #[test]
+    fn shared_cache() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let addr = stub_server(move |query| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            dual_stack(query)
+        });
+        let mut conf = ResolvConf::new();
+        conf.servers.push(ServerConf::new(addr, Transport::Udp));
+        conf.finalize();
+
+        let cache: Arc<dyn Cache> = Arc::new(LruCache::new(&conf.options));
+        let first = Resolver::with_cache(conf.clone(), cache.clone());
+        let second = Resolver::with_cache(conf.clone(), cache.clone());
+        slings::block_on(first.lookup_host("example.com")).unwrap();
+        slings::block_on(second.lookup_host("example.com")).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.len(), 2);
+
+        let resolver = Resolver::with_cache(conf, Arc::new(NoCache));
+        slings::block_on(resolver.lookup_host("example.com")).unwrap();
+        slings::block_on(resolver.lookup_host("example.com")).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 6);
+    }
+
+    #[test]
+    fn lookup_host_dual_stack() {
+        let addr = stub_server(dual_stack);
+        let v4 = IpAddr::from(Ipv4Addr::new(192, 0, 2, 1));
+        let v6 = IpAddr::from(Ipv6Addr::LOCALHOST);
+
+        let resolver = resolver(addr, ResolvOptions::default());
+        let ips = slings::block_on(resolver.lookup_host("example.com")).unwrap();
+        assert_eq!(ips, vec![v4, v6]);
+
+        let options = ResolvOptions {
+            use_inet6: true,
+            single_request: true,
+            ..Default::default()
+        };
+        let resolver = self::resolver(addr, options);
+        let ips = slings::block_on(resolver.lookup_host("example.com")).unwrap();
+        assert_eq!(ips, vec![v6, v4]);
+    }
+}
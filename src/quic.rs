@@ -0,0 +1,370 @@
+//! DNS over QUIC.
+//!
+//! Each query is sent on its own bidirectional stream of a long-lived
+//! connection to the server as described in RFC 9250. When the connection
+//! has to be reestablished, the query is sent as 0-RTT data if the server
+//! allows resuming the earlier session. Should the server reject the 0-RTT
+//! data, the query is sent again once the full handshake is done.
+//!
+//! QUIC is provided by quinn which needs the Tokio runtime.
+
+use std::convert::TryFrom;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::{fmt, io};
+
+use domain::base::message::Message;
+use futures_util::lock::Mutex as AsyncMutex;
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{ClientConfig, Connection, Endpoint, ReadError, ReadToEndError, WriteError};
+
+use crate::conf::ServerConf;
+use crate::tls::default_config;
+use crate::{Answer, QueryMessage};
+
+/// The ALPN protocol identifier of DNS over QUIC.
+const ALPN_DOQ: &[u8] = b"doq";
+
+//------------ QuicClient ----------------------------------------------------
+
+/// The QUIC client for a single server.
+pub(crate) struct QuicClient {
+    addr: SocketAddr,
+    name: String,
+    config: Option<ClientConfig>,
+    state: Mutex<Option<(Endpoint, Connection)>>,
+
+    /// Held while establishing a connection.
+    connecting: AsyncMutex<()>,
+}
+
+impl QuicClient {
+    pub fn new(conf: &ServerConf) -> Self {
+        let mut tls = conf.tls_config.clone().unwrap_or_else(default_config);
+        let config = Arc::make_mut(&mut tls);
+        config.alpn_protocols = vec![ALPN_DOQ.to_vec()];
+        config.enable_early_data = true;
+        QuicClient {
+            addr: conf.addr,
+            name: conf
+                .tls_name
+                .clone()
+                .unwrap_or_else(|| conf.addr.ip().to_string()),
+            config: QuicClientConfig::try_from(tls)
+                .ok()
+                .map(|config| ClientConfig::new(Arc::new(config))),
+            state: Mutex::new(None),
+            connecting: AsyncMutex::new(()),
+        }
+    }
+
+    pub async fn query(&self, query: &QueryMessage) -> io::Result<Answer> {
+        let conn = self.connection().await?;
+
+        // The message ID must be zero on the wire.
+        let mut buf = query.as_target().as_stream_slice().to_vec();
+        buf[2..4].copy_from_slice(&[0, 0]);
+        let buf = match exchange(&conn, &buf).await? {
+            Some(buf) => buf,
+            // The handshake has finished by now, so this isn't 0-RTT data.
+            None => exchange(&conn, &buf)
+                .await?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionReset, "0-RTT rejected"))?,
+        };
+        let len = match buf.get(..2) {
+            Some(len) => usize::from(u16::from_be_bytes([len[0], len[1]])),
+            None => return Err(io::Error::other("short buf")),
+        };
+        if buf.len() != len + 2 {
+            return Err(io::Error::other("short buf"));
+        }
+        let mut answer =
+            Message::from_octets(buf[2..].to_vec()).map_err(|_| io::Error::other("short buf"))?;
+        answer.header_mut().set_id(query.header().id());
+        if !answer.is_answer(&query.as_message()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "answer does not match query",
+            ));
+        }
+        Ok(answer.into())
+    }
+
+    /// Returns the connection to the server, establishing it if necessary.
+    ///
+    /// Only one query at a time establishes a connection. The others wait
+    /// and then use it.
+    async fn connection(&self) -> io::Result<Connection> {
+        if let Ok(conn) = self.current() {
+            return Ok(conn);
+        }
+        let _connecting = self.connecting.lock().await;
+        let endpoint = match self.current() {
+            Ok(conn) => return Ok(conn),
+            Err(endpoint) => endpoint,
+        };
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint,
+            None => self.endpoint()?,
+        };
+        let config = self
+            .config
+            .clone()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid TLS config"))?;
+        let connecting = endpoint
+            .connect_with(config, self.addr, &self.name)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let conn = match connecting.into_0rtt() {
+            Ok((conn, _)) => conn,
+            Err(connecting) => connecting.await?,
+        };
+        *self.state.lock().unwrap() = Some((endpoint, conn.clone()));
+        Ok(conn)
+    }
+
+    /// Returns the open connection or else the endpoint, if there is one.
+    fn current(&self) -> Result<Connection, Option<Endpoint>> {
+        match *self.state.lock().unwrap() {
+            Some((_, ref conn)) if conn.close_reason().is_none() => Ok(conn.clone()),
+            Some((ref endpoint, _)) => Err(Some(endpoint.clone())),
+            None => Err(None),
+        }
+    }
+
+    /// Creates the local endpoint for talking to the server.
+    fn endpoint(&self) -> io::Result<Endpoint> {
+        let local: SocketAddr = if self.addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        Endpoint::client(local)
+    }
+}
+
+/// Sends the message in `buf` on a new stream and returns the answer.
+///
+/// Returns `None` if the message was sent as 0-RTT data and the server
+/// rejected that.
+async fn exchange(conn: &Connection, buf: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let (mut send, mut recv) = conn.open_bi().await?;
+    match send.write_all(buf).await {
+        Err(WriteError::ZeroRttRejected) => return Ok(None),
+        res => res?,
+    }
+    send.finish()?;
+    match recv.read_to_end(usize::from(u16::MAX) + 2).await {
+        Ok(buf) => Ok(Some(buf)),
+        Err(ReadToEndError::Read(ReadError::ZeroRttRejected)) => Ok(None),
+        Err(err) => Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+    }
+}
+
+impl fmt::Debug for QuicClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QuicClient")
+            .field("addr", &self.addr)
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::conf::{ResolvConf, Transport};
+    use crate::Resolver;
+    use domain::base::iana::{Rcode, Rtype};
+    use domain::base::message_builder::{MessageBuilder, StreamTarget};
+    use domain::base::Name;
+    use domain::rdata::A;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Creates a CA and a certificate for `name` signed by it.
+    fn certificate(
+        name: &str,
+    ) -> (
+        CertificateDer<'static>,
+        CertificateDer<'static>,
+        PrivateKeyDer<'static>,
+    ) {
+        use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![name.into()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+        let key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key.serialize_der()));
+        (ca.der().clone(), cert.der().clone(), key)
+    }
+
+    /// Starts a DNS-over-QUIC server answering every A query.
+    ///
+    /// The server accepts 0-RTT data for the sessions it has issued.
+    /// Returns the server address, the number of connections accepted so
+    /// far, and the number of queries received as 0-RTT data.
+    fn stub_server(
+        cert: CertificateDer<'static>,
+        key: PrivateKeyDer<'static>,
+    ) -> (SocketAddr, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        use quinn::crypto::rustls::QuicServerConfig;
+
+        let mut tls = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)
+            .unwrap();
+        tls.alpn_protocols = vec![ALPN_DOQ.to_vec()];
+        tls.max_early_data_size = u32::MAX;
+        let config =
+            quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls).unwrap()));
+        let endpoint = Endpoint::server(config, ([127, 0, 0, 1], 0).into()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let early = Arc::new(AtomicUsize::new(0));
+        let (accept_counter, early_counter) = (accepted.clone(), early.clone());
+        tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                accept_counter.fetch_add(1, Ordering::SeqCst);
+                let conn = match incoming.accept().unwrap().into_0rtt() {
+                    Ok((conn, _)) => conn,
+                    Err(_) => unreachable!("servers always accept 0.5-RTT"),
+                };
+                let early_counter = early_counter.clone();
+                tokio::spawn(async move {
+                    while let Ok((mut send, mut recv)) = conn.accept_bi().await {
+                        if recv.is_0rtt() {
+                            early_counter.fetch_add(1, Ordering::SeqCst);
+                        }
+                        let buf = match recv.read_to_end(65537).await {
+                            Ok(buf) => buf,
+                            Err(_) => continue,
+                        };
+                        let query = Message::from_octets(buf[2..].to_vec()).unwrap();
+                        assert_eq!(query.header().id(), 0);
+                        let question = query.first_question().unwrap();
+                        let mut answer = MessageBuilder::new_vec()
+                            .start_answer(&query, Rcode::NOERROR)
+                            .unwrap();
+                        answer
+                            .push((question.qname(), 60, A::new([192, 0, 2, 1].into())))
+                            .unwrap();
+                        let answer = answer.finish();
+                        send.write_all(&(answer.len() as u16).to_be_bytes())
+                            .await
+                            .unwrap();
+                        send.write_all(&answer).await.unwrap();
+                        send.finish().unwrap();
+                    }
+                });
+            }
+        });
+        (addr, accepted, early)
+    }
+
+    /// Returns the configuration of the server at `addr` with the CA.
+    fn server_conf(addr: SocketAddr, ca: CertificateDer<'static>) -> ServerConf {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca).unwrap();
+        let mut server = ServerConf::new(addr, Transport::Quic);
+        server.tls_name = Some("dns.example".into());
+        server.tls_config = Some(Arc::new(
+            rustls::ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        server
+    }
+
+    /// Sends an A query for `name` with `client`.
+    async fn query(client: &QuicClient, name: &str) -> io::Result<Answer> {
+        let qname = Name::<Vec<u8>>::from_str(name).unwrap();
+        let mut message = MessageBuilder::from_target(StreamTarget::new_vec())
+            .unwrap()
+            .question();
+        message.push((&qname, Rtype::A)).unwrap();
+        client.query(&message.additional()).await
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn quic_transport() {
+        runtime().block_on(async {
+            let (ca, cert, key) = certificate("dns.example");
+            let (addr, accepted, _) = stub_server(cert, key);
+            let mut conf = ResolvConf::new();
+            conf.servers.push(server_conf(addr, ca));
+            conf.finalize();
+            let resolver = Resolver::from_conf(conf);
+
+            for name in &["example.com", "example.org"] {
+                let qname = Name::<Vec<u8>>::from_str(name).unwrap();
+                let answer = resolver.query((&qname, Rtype::A)).await.unwrap();
+                assert_eq!(answer.header_counts().ancount(), 1);
+            }
+            assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn quic_concurrent_connect() {
+        runtime().block_on(async {
+            let (ca, cert, key) = certificate("dns.example");
+            let (addr, accepted, _) = stub_server(cert, key);
+            let client = QuicClient::new(&server_conf(addr, ca));
+            let (first, second) = futures_util::future::join(
+                query(&client, "example.com"),
+                query(&client, "example.org"),
+            )
+            .await;
+            first.unwrap();
+            second.unwrap();
+            assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn quic_resumption() {
+        runtime().block_on(async {
+            let (ca, cert, key) = certificate("dns.example");
+            let (addr, accepted, early) = stub_server(cert.clone(), key.clone_key());
+            let server = server_conf(addr, ca);
+            let client = QuicClient::new(&server);
+            query(&client, "example.com").await.unwrap();
+            assert_eq!(early.load(Ordering::SeqCst), 0);
+
+            // The next query resumes the session with 0-RTT data.
+            let conn = client.current().unwrap();
+            conn.close(0u32.into(), b"");
+            conn.closed().await;
+            query(&client, "example.org").await.unwrap();
+            assert_eq!(accepted.load(Ordering::SeqCst), 2);
+            assert_eq!(early.load(Ordering::SeqCst), 1);
+
+            // A server that doesn't know the session rejects the 0-RTT
+            // data. Clients share sessions through the TLS config.
+            let (other, accepted, early) = stub_server(cert, key);
+            let mut server = server;
+            server.addr = other;
+            let client = QuicClient::new(&server);
+            let answer = query(&client, "example.net").await.unwrap();
+            assert_eq!(answer.header_counts().ancount(), 1);
+            assert_eq!(accepted.load(Ordering::SeqCst), 1);
+            assert_eq!(early.load(Ordering::SeqCst), 0);
+        });
+    }
+}
@@ -0,0 +1,323 @@
+//! Latency and failure tracking for servers.
+//!
+//! Each server keeps a smoothed round-trip time of its answers and the
+//! number of times in a row it has failed to answer. With the
+//! `prefer_fastest` option, queries go to the healthy server with the
+//! lowest smoothed round-trip time first. Every now and then, a query is
+//! sent to another server first instead so that changes in latency are
+//! noticed.
+//!
+//! A server that fails too many queries in a row is considered down and
+//! skipped by queries. It is probed again after a backoff time that
+//! doubles with each failed probe until one succeeds.
+//!
+//! Servers also keep track of whether they support optional features such
+//! as randomizing the case of the query name. A feature is only given up on
+//! after failing several times in a row, so that a single lost or spoofed
+//! response doesn’t turn it off, and is tried again after a while.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+//------------ ServerStats ---------------------------------------------------
+
+/// The weight of a new sample in the smoothed round-trip time.
+///
+/// This is the value suggested by RFC 6298.
+const SRTT_WEIGHT: u32 = 8;
+
+/// The chance of sending a query to a random server first.
+const PROBE_CHANCE: f64 = 0.05;
+
+/// The number of failures in a row after which a server is down.
+const DOWN_AFTER: u32 = 3;
+
+/// The time before a server that went down is probed for the first time.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The maximum time between probes of a server that is down.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Latency and failure statistics of a single server.
+#[derive(Debug, Default)]
+pub(crate) struct ServerStats {
+    state: Mutex<StatsState>,
+}
+
+#[derive(Debug, Default)]
+struct StatsState {
+    /// The smoothed round-trip time or `None` if there is no sample yet.
+    srtt: Option<Duration>,
+
+    /// The number of failed queries since the last successful one.
+    failures: u32,
+
+    /// The time of the last successful query.
+    last_success: Option<Instant>,
+
+    /// The time the server is to be probed next if it is down.
+    next_probe: Option<Instant>,
+}
+
+impl ServerStats {
+    /// Records an answer received after `rtt`.
+    pub fn success(&self, rtt: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.srtt = Some(smooth(state.srtt, rtt));
+        state.failures = 0;
+        state.last_success = Some(Instant::now());
+        state.next_probe = None;
+    }
+
+    /// Records a query that failed after `elapsed`.
+    ///
+    /// The time is counted towards the smoothed round-trip time so that a
+    /// server that keeps timing out becomes slow.
+    pub fn failure(&self, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.srtt = Some(smooth(state.srtt, elapsed));
+        state.failures = state.failures.saturating_add(1);
+        if state.failures >= DOWN_AFTER {
+            state.next_probe = Some(Instant::now() + backoff(state.failures));
+        }
+    }
+
+    /// Returns the smoothed round-trip time.
+    ///
+    /// Servers that haven’t been queried yet have a time of zero so that
+    /// they are tried early on.
+    pub fn srtt(&self) -> Duration {
+        self.state.lock().unwrap().srtt.unwrap_or_default()
+    }
+
+    /// Returns the number of failed queries since the last successful one.
+    pub fn failures(&self) -> u32 {
+        self.state.lock().unwrap().failures
+    }
+
+    /// Returns whether the server answered the last query sent to it.
+    pub fn is_healthy(&self) -> bool {
+        self.failures() == 0
+    }
+
+    /// Returns whether the server has failed too often to be queried.
+    pub fn is_down(&self) -> bool {
+        self.failures() >= DOWN_AFTER
+    }
+
+    /// Returns the time of the last successful query.
+    pub fn last_success(&self) -> Option<Instant> {
+        self.state.lock().unwrap().last_success
+    }
+
+    /// Returns whether a server that is down should be probed now.
+    ///
+    /// If so, the next probe is scheduled so that only one probe is sent
+    /// until its outcome is known.
+    pub fn take_probe(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match state.next_probe {
+            Some(next) if next <= now => {
+                state.next_probe = Some(now + backoff(state.failures));
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Returns the time until the next probe after `failures` in a row.
+fn backoff(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(DOWN_AFTER).min(16);
+    (MIN_BACKOFF * 2u32.pow(doublings)).min(MAX_BACKOFF)
+}
+
+/// Adds `sample` to the smoothed round-trip time `srtt`.
+fn smooth(srtt: Option<Duration>, sample: Duration) -> Duration {
+    match srtt {
+        Some(srtt) => (srtt * (SRTT_WEIGHT - 1) + sample) / SRTT_WEIGHT,
+        None => sample,
+    }
+}
+
+/// Returns the order in which to try servers with the given statistics.
+///
+/// Healthy servers come first, each group sorted by smoothed round-trip
+/// time. Occasionally, a random server is moved to the front.
+pub(crate) fn fastest_first<'a, I>(stats: I) -> Vec<usize>
+where
+    I: IntoIterator<Item = &'a ServerStats>,
+{
+    let mut keys: Vec<_> = stats
+        .into_iter()
+        .map(|stats| (!stats.is_healthy(), stats.srtt()))
+        .enumerate()
+        .collect();
+    keys.sort_by_key(|&(_, key)| key);
+    let mut order: Vec<_> = keys.into_iter().map(|(idx, _)| idx).collect();
+    if order.len() > 1 && rand::random::<f64>() < PROBE_CHANCE {
+        let probe = rand::random::<usize>() % (order.len() - 1) + 1;
+        order.swap(0, probe);
+    }
+    order
+}
+
+//------------ Support -------------------------------------------------------
+
+/// The number of failures in a row after which a feature is given up on.
+const UNSUPPORTED_AFTER: u32 = 3;
+
+/// Whether a server supports an optional feature.
+///
+/// A feature that failed `UNSUPPORTED_AFTER` times in a row is not used
+/// until `retry` has passed. It is then tried again and given up on for
+/// another `retry` right away if it fails once more.
+#[derive(Debug)]
+pub(crate) struct Support {
+    retry: Duration,
+    state: Mutex<SupportState>,
+}
+
+#[derive(Debug, Default)]
+struct SupportState {
+    /// The number of failures since the feature last worked.
+    failures: u32,
+
+    /// The time the feature was last given up on.
+    given_up: Option<Instant>,
+}
+
+impl Support {
+    /// Creates a feature that is tried again `retry` after giving up on it.
+    pub fn new(retry: Duration) -> Self {
+        Support {
+            retry,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Returns whether the feature should be used.
+    pub fn is_supported(&self) -> bool {
+        match self.state.lock().unwrap().given_up {
+            Some(given_up) => given_up.elapsed() >= self.retry,
+            None => true,
+        }
+    }
+
+    /// Records that the feature worked.
+    pub fn success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures = 0;
+        state.given_up = None;
+    }
+
+    /// Records that the feature failed.
+    pub fn failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures = state.failures.saturating_add(1);
+        if state.failures >= UNSUPPORTED_AFTER {
+            state.given_up = Some(Instant::now());
+        }
+    }
+
+    /// Gives up on the feature right away.
+    ///
+    /// This is for failures that clearly show the feature isn’t supported.
+    pub fn give_up(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures = UNSUPPORTED_AFTER;
+        state.given_up = Some(Instant::now());
+    }
+}
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn smoothed_rtt() {
+        let stats = ServerStats::default();
+        assert_eq!(stats.srtt(), Duration::ZERO);
+        stats.success(Duration::from_millis(80));
+        assert_eq!(stats.srtt(), Duration::from_millis(80));
+        stats.success(Duration::from_millis(160));
+        assert_eq!(stats.srtt(), Duration::from_millis(90));
+        stats.failure(Duration::from_millis(890));
+        assert_eq!(stats.srtt(), Duration::from_millis(190));
+        assert_eq!(stats.failures(), 1);
+        assert!(!stats.is_healthy());
+        stats.success(Duration::from_millis(190));
+        assert!(stats.is_healthy());
+    }
+
+    #[test]
+    fn down() {
+        let stats = ServerStats::default();
+        for _ in 0..DOWN_AFTER - 1 {
+            stats.failure(Duration::from_secs(5));
+        }
+        assert!(!stats.is_down());
+        assert!(!stats.take_probe());
+        stats.failure(Duration::from_secs(5));
+        assert!(stats.is_down());
+        assert!(!stats.take_probe());
+        stats.state.lock().unwrap().next_probe = Some(Instant::now());
+        assert!(stats.take_probe());
+        assert!(!stats.take_probe());
+        stats.success(Duration::from_millis(10));
+        assert!(!stats.is_down());
+        assert!(stats.last_success().is_some());
+        assert!(!stats.take_probe());
+
+        assert_eq!(backoff(DOWN_AFTER), MIN_BACKOFF);
+        assert_eq!(backoff(DOWN_AFTER + 2), MIN_BACKOFF * 4);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn support() {
+        let support = Support::new(Duration::from_secs(60));
+        for _ in 0..UNSUPPORTED_AFTER - 1 {
+            support.failure();
+        }
+        assert!(support.is_supported());
+        support.success();
+        for _ in 0..UNSUPPORTED_AFTER - 1 {
+            support.failure();
+        }
+        assert!(support.is_supported());
+        support.failure();
+        assert!(!support.is_supported());
+
+        let retry = Instant::now() - Duration::from_secs(60);
+        support.state.lock().unwrap().given_up = Some(retry);
+        assert!(support.is_supported());
+        support.failure();
+        assert!(!support.is_supported());
+        support.success();
+        assert!(support.is_supported());
+        support.give_up();
+        assert!(!support.is_supported());
+    }
+
+    #[test]
+    fn order() {
+        let stats: Vec<_> = (0..3).map(|_| ServerStats::default()).collect();
+        stats[0].success(Duration::from_millis(300));
+        stats[1].failure(Duration::from_millis(10));
+        stats[2].success(Duration::from_millis(20));
+        let orders: Vec<_> = (0..1000).map(|_| fastest_first(&stats)).collect();
+        let expected = vec![2, 0, 1];
+        let usual = orders.iter().filter(|order| **order == expected).count();
+        assert!(usual > 900);
+        assert!(usual < 1000);
+        assert!(orders.iter().all(|order| {
+            let mut sorted = order.clone();
+            sorted.sort_unstable();
+            sorted == [0, 1, 2]
+        }));
+    }
+}
@@ -0,0 +1,642 @@
+//! Persistent TCP connections.
+//!
+//! With the `stay_open` option, each TCP or TLS server keeps a [`TcpPool`]
+//! that holds on to an open connection between queries. With the tokio and
+//! awak runtimes, all threads share this single connection. The slings
+//! runtime runs an executor of its own on every thread and its sockets can
+//! only be used on the thread they were created on, so there is one
+//! connection per thread instead. Any number of queries can be outstanding
+//! on a connection at the same time. Each query is sent with a random
+//! message ID unique on the connection and a background task reads the
+//! answers and hands them to the waiting queries by that ID.
+//!
+//! If the server closes the connection, the next query opens a new one. A
+//! query that finds out it was sent over a connection that has since been
+//! closed is retried once on a fresh connection. Connections that are
+//! given up are closed by ending their background task, which drops the
+//! socket. Dropping a pool closes all its connections, including those on
+//! other threads. As slings tasks can only be woken on their own thread, the
+//! background tasks also check every idle timeout whether their connection
+//! has been closed from elsewhere.
+//!
+//! Queries announce the wish to keep the connection open through the
+//! edns-tcp-keepalive option of RFC 7828. A server may answer with the time
+//! it keeps idle connections open, otherwise the `tcp_idle_timeout` option
+//! is used. A connection that has been idle for longer than that is closed
+//! by its background task, as RFC 7828 asks of clients, and isn’t used
+//! anymore, so queries don’t race against the server closing it.
+
+#[cfg(feature = "slings-runtime")]
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+#[cfg(feature = "slings-runtime")]
+use std::rc::{Rc, Weak as RcWeak};
+#[cfg(feature = "slings-runtime")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Poll, Waker};
+#[cfg(feature = "slings-runtime")]
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+use std::{fmt, io};
+
+use domain::base::message::Message;
+use futures_util::future::{poll_fn, select, Either};
+use futures_util::lock::Mutex as AsyncMutex;
+use futures_util::pin_mut;
+
+#[cfg(feature = "tls")]
+use crate::tls::TlsClient;
+use crate::{spawn, split_stream, timeout, Answer, QueryMessage};
+use crate::{AsyncReadExt, AsyncWriteExt, StreamReadHalf, StreamWriteHalf, TcpStream};
+
+//------------ Connector -----------------------------------------------------
+
+/// How the connections of a pool are opened.
+#[derive(Clone, Debug)]
+pub(crate) enum Connector {
+    /// Plain TCP connections to the address.
+    Tcp(SocketAddr),
+
+    /// TLS connections made by the client.
+    #[cfg(feature = "tls")]
+    Tls(TlsClient),
+}
+
+impl Connector {
+    /// Opens a new connection and splits it into its halves.
+    async fn connect(&self) -> io::Result<(StreamReadHalf, StreamWriteHalf)> {
+        match self {
+            Connector::Tcp(addr) => Ok(split_stream(TcpStream::connect(addr).await?)),
+            #[cfg(feature = "tls")]
+            Connector::Tls(tls) => Ok(split_stream(tls.connect().await?)),
+        }
+    }
+}
+
+//------------ TcpPool -------------------------------------------------------
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "slings-runtime")] {
+        /// A reference to a connection.
+        ///
+        /// Sockets are bound to the thread of their runtime here.
+        type ConnRef = Rc<Connection>;
+        type ConnWeak = RcWeak<Connection>;
+
+        thread_local! {
+            /// The open connections of this thread by pool.
+            static CONNECTIONS: RefCell<HashMap<usize, ConnRef>> = RefCell::new(HashMap::new());
+        }
+
+        /// The source of pool identifiers.
+        static NEXT_POOL: AtomicUsize = AtomicUsize::new(0);
+    }
+    else {
+        /// A reference to a connection.
+        type ConnRef = Arc<Connection>;
+        type ConnWeak = Weak<Connection>;
+    }
+}
+
+/// The persistent connection to a single server.
+///
+/// With the slings runtime, the pool keeps one connection per thread and
+/// remembers all of them so they can be closed when the pool is dropped.
+pub(crate) struct TcpPool {
+    connector: Connector,
+    idle_timeout: Duration,
+    #[cfg(feature = "slings-runtime")]
+    id: usize,
+    #[cfg(feature = "slings-runtime")]
+    connections: Mutex<Vec<Weak<Shared>>>,
+    #[cfg(not(feature = "slings-runtime"))]
+    connection: Mutex<Option<ConnRef>>,
+}
+
+impl TcpPool {
+    /// Creates a pool keeping idle connections for `idle_timeout`.
+    ///
+    /// The timeout is used until the server announces its own.
+    pub fn new(connector: Connector, idle_timeout: Duration) -> Self {
+        TcpPool {
+            connector,
+            idle_timeout,
+            #[cfg(feature = "slings-runtime")]
+            id: NEXT_POOL.fetch_add(1, Ordering::Relaxed),
+            #[cfg(feature = "slings-runtime")]
+            connections: Mutex::default(),
+            #[cfg(not(feature = "slings-runtime"))]
+            connection: Mutex::default(),
+        }
+    }
+
+    /// Sends `query` over the pooled connection and waits for its answer.
+    pub async fn query(&self, query: &QueryMessage) -> io::Result<Answer> {
+        let (conn, fresh) = self.connection();
+        match conn.query(query).await {
+            Err(err) if !fresh && is_closed(&err) => self.connection().0.query(query).await,
+            res => res,
+        }
+    }
+
+    /// Returns a connection that can be used and whether it is new.
+    ///
+    /// A new connection is only established by its first query, so that
+    /// queries started at the same time all end up on it.
+    #[cfg(feature = "slings-runtime")]
+    fn connection(&self) -> (ConnRef, bool) {
+        CONNECTIONS.with(|conns| {
+            let mut conns = conns.borrow_mut();
+            // This also cleans up after pools that have been dropped.
+            conns.retain(|_, conn| !conn.shared.is_closed());
+            if let Some(conn) = conns.get(&self.id) {
+                if conn.is_usable() {
+                    return (conn.clone(), false);
+                }
+            }
+            let conn = self.new_connection();
+            let mut connections = self.connections.lock().unwrap();
+            connections.retain(|shared| shared.strong_count() > 0);
+            connections.push(Arc::downgrade(&conn.shared));
+            conns.insert(self.id, conn.clone());
+            (conn, true)
+        })
+    }
+
+    /// Returns a connection that can be used and whether it is new.
+    ///
+    /// A new connection is only established by its first query, so that
+    /// queries started at the same time all end up on it.
+    #[cfg(not(feature = "slings-runtime"))]
+    fn connection(&self) -> (ConnRef, bool) {
+        let mut current = self.connection.lock().unwrap();
+        if let Some(ref conn) = *current {
+            if conn.is_usable() {
+                return (conn.clone(), false);
+            }
+        }
+        let conn = self.new_connection();
+        *current = Some(conn.clone());
+        (conn, true)
+    }
+
+    fn new_connection(&self) -> ConnRef {
+        ConnRef::new_cyclic(|this| {
+            Connection::new(this.clone(), self.connector.clone(), self.idle_timeout)
+        })
+    }
+}
+
+impl Drop for TcpPool {
+    fn drop(&mut self) {
+        #[cfg(feature = "slings-runtime")]
+        {
+            // The reader tasks on the other threads drop their sockets.
+            for shared in self.connections.get_mut().unwrap().drain(..) {
+                if let Some(shared) = shared.upgrade() {
+                    shared.close(&pool_dropped());
+                }
+            }
+            let _ = CONNECTIONS.try_with(|conns| {
+                if let Some(conn) = conns.borrow_mut().remove(&self.id) {
+                    conn.retire(&pool_dropped());
+                }
+            });
+        }
+        #[cfg(not(feature = "slings-runtime"))]
+        if let Some(conn) = self.connection.get_mut().unwrap().take() {
+            conn.retire(&pool_dropped());
+        }
+    }
+}
+
+impl fmt::Debug for TcpPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TcpPool")
+            .field("connector", &self.connector)
+            .field("idle_timeout", &self.idle_timeout)
+            .finish()
+    }
+}
+
+/// Returns the error for queries on a connection of a dropped pool.
+fn pool_dropped() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "pool dropped")
+}
+
+/// Returns the error for queries on a connection closed for being idle.
+fn connection_idle() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "connection idle")
+}
+
+/// Returns whether `err` means the connection went away underneath us.
+fn is_closed(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::NotConnected
+    )
+}
+
+//------------ Connection ----------------------------------------------------
+
+/// A single connection.
+///
+/// The socket is connected when the first query is written. Its read half
+/// is then handed to the reader task, which also drops the write half when
+/// the connection is closed.
+struct Connection {
+    this: ConnWeak,
+    connector: Connector,
+    writer: AsyncMutex<Option<StreamWriteHalf>>,
+    shared: Arc<Shared>,
+}
+
+impl Connection {
+    fn new(this: ConnWeak, connector: Connector, idle_timeout: Duration) -> Self {
+        Connection {
+            this,
+            connector,
+            writer: AsyncMutex::new(None),
+            shared: Arc::new(Shared::new(idle_timeout)),
+        }
+    }
+
+    /// Returns whether the connection can take more queries.
+    ///
+    /// A connection that has been idle for too long is closed.
+    fn is_usable(&self) -> bool {
+        if self.shared.is_expired() {
+            self.retire(&connection_idle());
+        }
+        !self.shared.is_closed()
+    }
+
+    /// Closes the connection and drops our half of the socket.
+    ///
+    /// The reader task drops the other half once it notices.
+    fn retire(&self, err: &io::Error) {
+        self.shared.close(err);
+        self.drop_writer();
+    }
+
+    /// Drops the write half of the socket unless a query is writing.
+    fn drop_writer(&self) {
+        if let Some(mut writer) = self.writer.try_lock() {
+            writer.take();
+        }
+    }
+
+    /// Connects the socket unless that has happened already.
+    async fn connect(&self, writer: &mut Option<StreamWriteHalf>) -> io::Result<()> {
+        if writer.is_some() {
+            return Ok(());
+        }
+        if self.shared.is_closed() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "connection closed",
+            ));
+        }
+        match self.connector.connect().await {
+            Ok((reader, sock)) => {
+                spawn(read_loop(self.this.clone(), self.shared.clone(), reader));
+                *writer = Some(sock);
+                Ok(())
+            }
+            Err(err) => {
+                self.shared.close(&err);
+                Err(err)
+            }
+        }
+    }
+
+    async fn query(&self, query: &QueryMessage) -> io::Result<Answer> {
+        let (id, pending) = self.shared.register()?;
+        let guard = PendingGuard {
+            shared: &self.shared,
+            id,
+            pending: &pending,
+        };
+
+        // The message goes out with our ID in place of its own.
+        let mut buf = query.as_target().as_stream_slice().to_vec();
+        buf[2..4].copy_from_slice(&id.to_be_bytes());
+        {
+            let mut writer = self.writer.lock().await;
+            self.connect(&mut writer).await?;
+            let writer = writer.as_mut().unwrap();
+            let mut write = WriteGuard {
+                shared: &self.shared,
+                done: false,
+            };
+            // TLS streams may hold on to the data until flushed.
+            let res = match writer.write_all(&buf).await {
+                Ok(()) => writer.flush().await,
+                Err(err) => Err(err),
+            };
+            write.done = true;
+            if let Err(err) = res {
+                self.shared.close(&err);
+                return Err(err);
+            }
+        }
+
+        let buf = pending.wait().await?;
+        drop(guard);
+        let mut answer = Message::from_octets(buf).map_err(|_| io::Error::other("short buf"))?;
+        answer.header_mut().set_id(query.header().id());
+        if !answer.is_answer(&query.as_message()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "answer does not match query",
+            ));
+        }
+        Ok(answer.into())
+    }
+}
+
+/// Reads answers from the connection until it is closed.
+///
+/// The connection is closed once it has been idle for too long. Once the
+/// connection is closed, on either side, both halves of the socket are
+/// dropped.
+async fn read_loop(conn: ConnWeak, shared: Arc<Shared>, sock: StreamReadHalf) {
+    let read = read_answers(&shared, sock);
+    pin_mut!(read);
+    loop {
+        if shared.is_expired() {
+            shared.close(&connection_idle());
+            break;
+        }
+        // Closing the connection on another thread doesn't wake us.
+        let closed = timeout(shared.idle_left(), shared.closed());
+        pin_mut!(closed);
+        match select(read.as_mut(), closed).await {
+            Either::Left((err, _)) => {
+                shared.close(&err);
+                break;
+            }
+            Either::Right((Ok(()), _)) => break,
+            Either::Right((Err(_), _)) => {}
+        }
+    }
+    if let Some(conn) = conn.upgrade() {
+        conn.drop_writer();
+    }
+}
+
+/// Reads answers from `sock` until that fails.
+async fn read_answers(shared: &Shared, mut sock: StreamReadHalf) -> io::Error {
+    loop {
+        let mut len_buf = [0u8; 2];
+        if let Err(err) = sock.read_exact(&mut len_buf).await {
+            break err;
+        }
+        let mut buf = vec![0; u16::from_be_bytes(len_buf) as usize];
+        if let Err(err) = sock.read_exact(&mut buf).await {
+            break err;
+        }
+        if buf.len() >= 2 {
+            shared.deliver(u16::from_be_bytes([buf[0], buf[1]]), buf);
+        }
+    }
+}
+
+//------------ Shared --------------------------------------------------------
+
+/// The state shared between a connection and its reader task.
+struct Shared {
+    state: Mutex<SharedState>,
+
+    /// The thread whose runtime the reader task runs on.
+    #[cfg(feature = "slings-runtime")]
+    thread: ThreadId,
+}
+
+struct SharedState {
+    closed: bool,
+    pending: HashMap<u16, Arc<Pending>>,
+
+    /// How long the connection may stay idle.
+    idle_timeout: Duration,
+
+    /// The time a query was last sent or answered.
+    last_active: Option<Instant>,
+
+    /// The reader task waiting for the connection to be closed.
+    reader: Option<Waker>,
+}
+
+impl Shared {
+    fn new(idle_timeout: Duration) -> Self {
+        Shared {
+            state: Mutex::new(SharedState {
+                closed: false,
+                pending: HashMap::new(),
+                idle_timeout,
+                last_active: None,
+                reader: None,
+            }),
+            #[cfg(feature = "slings-runtime")]
+            thread: thread::current().id(),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// Waits until the connection is closed.
+    async fn closed(&self) {
+        poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            if state.closed {
+                Poll::Ready(())
+            } else {
+                state.reader = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Returns how long the connection can stay idle from now on.
+    ///
+    /// This is the full idle timeout while queries are pending.
+    fn idle_left(&self) -> Duration {
+        let state = self.state.lock().unwrap();
+        match state.last_active {
+            Some(last) if state.pending.is_empty() => {
+                state.idle_timeout.saturating_sub(last.elapsed())
+            }
+            _ => state.idle_timeout,
+        }
+    }
+
+    /// Returns whether the connection has been idle for too long.
+    fn is_expired(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        match state.last_active {
+            Some(last) => state.pending.is_empty() && last.elapsed() >= state.idle_timeout,
+            None => false,
+        }
+    }
+
+    /// Picks a random unused message ID and registers a pending answer for it.
+    fn register(&self) -> io::Result<(u16, Arc<Pending>)> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "connection closed",
+            ));
+        }
+        if state.pending.len() > usize::from(u16::MAX) {
+            return Err(io::Error::other("too many queries on connection"));
+        }
+        let mut id = rand::random();
+        while state.pending.contains_key(&id) {
+            id = rand::random();
+        }
+        let pending = Arc::new(Pending::default());
+        state.pending.insert(id, pending.clone());
+        state.last_active = Some(Instant::now());
+        Ok((id, pending))
+    }
+
+    /// Removes `pending` unless its answer has been delivered already.
+    fn unregister(&self, id: u16, pending: &Arc<Pending>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(current) = state.pending.get(&id) {
+            if Arc::ptr_eq(current, pending) {
+                state.pending.remove(&id);
+            }
+        }
+    }
+
+    /// Hands an answer to the query waiting for `id`.
+    ///
+    /// Answers nobody is waiting for anymore are dropped. An idle timeout
+    /// announced by the server replaces the current one.
+    fn deliver(&self, id: u16, buf: Vec<u8>) {
+        let pending = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(timeout) = idle_timeout(&buf) {
+                state.idle_timeout = timeout;
+            }
+            state.last_active = Some(Instant::now());
+            state.pending.remove(&id)
+        };
+        if let Some(pending) = pending {
+            pending.finish(Ok(buf));
+        }
+    }
+
+    /// Marks the connection as closed and fails all pending queries.
+    ///
+    /// On another thread, the reader task is left to notice by itself.
+    fn close(&self, err: &io::Error) {
+        let (pending, reader) = {
+            let mut state = self.state.lock().unwrap();
+            state.closed = true;
+            #[cfg(feature = "slings-runtime")]
+            let reader = if self.thread == thread::current().id() {
+                state.reader.take()
+            } else {
+                None
+            };
+            #[cfg(not(feature = "slings-runtime"))]
+            let reader = state.reader.take();
+            (std::mem::take(&mut state.pending), reader)
+        };
+        if let Some(reader) = reader {
+            reader.wake();
+        }
+        for pending in pending.into_values() {
+            pending.finish(Err(io::Error::new(err.kind(), err.to_string())));
+        }
+    }
+}
+
+/// Returns the idle timeout announced in the answer in `buf`, if any.
+fn idle_timeout(buf: &[u8]) -> Option<Duration> {
+    let answer = Message::from_octets(buf).ok()?;
+    let keepalive = answer.opt()?.opt().tcp_keepalive()?;
+    keepalive.timeout().map(Duration::from)
+}
+
+/// Unregisters a pending query that goes away before its answer arrives.
+struct PendingGuard<'a> {
+    shared: &'a Shared,
+    id: u16,
+    pending: &'a Arc<Pending>,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.shared.unregister(self.id, self.pending)
+    }
+}
+
+/// Closes the connection if a write is abandoned half way through.
+///
+/// Otherwise the next message written would be garbage to the server.
+struct WriteGuard<'a> {
+    shared: &'a Shared,
+    done: bool,
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.shared.close(&io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "write abandoned",
+            ));
+        }
+    }
+}
+
+//------------ Pending -------------------------------------------------------
+
+/// A query waiting for its answer.
+#[derive(Default)]
+struct Pending {
+    state: Mutex<PendingState>,
+}
+
+#[derive(Default)]
+struct PendingState {
+    result: Option<io::Result<Vec<u8>>>,
+    waker: Option<Waker>,
+}
+
+impl Pending {
+    fn finish(&self, result: io::Result<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        state.result = Some(result);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    async fn wait(&self) -> io::Result<Vec<u8>> {
+        poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            match state.result.take() {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
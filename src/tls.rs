@@ -0,0 +1,105 @@
+//! DNS over TLS.
+//!
+//! Queries are sent over a TLS connection to the server using the same
+//! length-prefixed framing as plain TCP, as described in RFC 7858. With the
+//! `stay_open` option, connections are kept open and shared by queries the
+//! same way as TCP connections. Otherwise each query uses its own
+//! connection.
+
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::{fmt, io};
+
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore};
+
+use crate::conf::ServerConf;
+use crate::{Answer, QueryMessage, ServerInfo, TcpStream};
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "tokio-runtime")] {
+        use tokio_rustls::client::TlsStream;
+        use tokio_rustls::TlsConnector;
+    }
+    else {
+        use futures_rustls::client::TlsStream;
+        use futures_rustls::TlsConnector;
+    }
+}
+
+//------------ TlsClient -----------------------------------------------------
+
+/// The TLS client for a single server.
+#[derive(Clone)]
+pub(crate) struct TlsClient {
+    addr: SocketAddr,
+    name: Option<String>,
+    connector: TlsConnector,
+}
+
+impl TlsClient {
+    pub fn new(conf: &ServerConf) -> Self {
+        Self::with_alpn(conf, conf.tls_name.clone(), Vec::new())
+    }
+
+    /// Creates a client verifying `name` and offering `alpn` protocols.
+    pub fn with_alpn(conf: &ServerConf, name: Option<String>, alpn: Vec<Vec<u8>>) -> Self {
+        let mut config = conf.tls_config.clone().unwrap_or_else(default_config);
+        if !alpn.is_empty() {
+            Arc::make_mut(&mut config).alpn_protocols = alpn;
+        }
+        TlsClient {
+            addr: conf.addr,
+            name,
+            connector: config.into(),
+        }
+    }
+
+    /// Returns the name the server certificate has to be valid for.
+    fn server_name(&self) -> io::Result<ServerName<'static>> {
+        match self.name {
+            Some(ref name) => ServerName::try_from(name.clone())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)),
+            None => Ok(self.addr.ip().into()),
+        }
+    }
+
+    /// Opens a new TLS connection to the server.
+    pub async fn connect(&self) -> io::Result<TlsStream<TcpStream>> {
+        let sock = TcpStream::connect(&self.addr).await?;
+        self.connect_over(sock).await
+    }
+
+    /// Starts a TLS connection to the server over the connected `sock`.
+    pub async fn connect_over(&self, sock: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+        let name = self.server_name()?;
+        self.connector.connect(name, sock).await
+    }
+
+    pub async fn query(&self, query: &QueryMessage) -> io::Result<Answer> {
+        let mut sock = self.connect().await?;
+        ServerInfo::stream_query(query, &mut sock).await
+    }
+}
+
+impl fmt::Debug for TlsClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsClient")
+            .field("addr", &self.addr)
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+/// Returns a client configuration trusting the Mozilla root certificates.
+pub(crate) fn default_config() -> Arc<ClientConfig> {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    )
+}
@@ -0,0 +1,95 @@
+//! Configuring encrypted transports through the public API.
+
+#![cfg(all(feature = "tls", feature = "slings-runtime"))]
+
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+
+use dns_resolver::{Padding, ResolvConf, Resolver, ServerConf, Transport};
+use domain::base::iana::{Rcode, Rtype};
+use domain::base::{Message, MessageBuilder, Name};
+use domain::rdata::A;
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+/// Starts a DNS-over-TLS server for `name` and returns its CA.
+///
+/// The server answers every A query with 192.0.2.1. Queries have to be
+/// padded to blocks of 256 octets.
+fn tls_server(name: &str) -> (SocketAddr, CertificateDer<'static>) {
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec![name.into()])
+        .unwrap()
+        .signed_by(&key, &ca, &ca_key)
+        .unwrap();
+    let key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key.serialize_der()));
+    let config = Arc::new(
+        rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.der().clone()], key)
+            .unwrap(),
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for sock in listener.incoming() {
+            let conn = rustls::ServerConnection::new(config.clone()).unwrap();
+            let mut sock = rustls::StreamOwned::new(conn, sock.unwrap());
+            thread::spawn(move || loop {
+                let mut len = [0u8; 2];
+                if sock.read_exact(&mut len).is_err() {
+                    return;
+                }
+                let mut buf = vec![0; u16::from_be_bytes(len).into()];
+                sock.read_exact(&mut buf).unwrap();
+                assert_eq!(buf.len() % 256, 0);
+                let query = Message::from_octets(buf).unwrap();
+                let question = query.first_question().unwrap();
+                let mut answer = MessageBuilder::new_vec()
+                    .start_answer(&query, Rcode::NOERROR)
+                    .unwrap();
+                answer
+                    .push((question.qname(), 60, A::new(Ipv4Addr::new(192, 0, 2, 1))))
+                    .unwrap();
+                let answer = answer.finish();
+                sock.write_all(&(answer.len() as u16).to_be_bytes())
+                    .unwrap();
+                sock.write_all(&answer).unwrap();
+                sock.flush().unwrap();
+            });
+        }
+    });
+    (addr, ca.der().clone())
+}
+
+#[test]
+fn tls_public_api() {
+    let (addr, ca) = tls_server("dns.example");
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(ca).unwrap();
+
+    let mut server = ServerConf::new(addr, Transport::Tls);
+    server.tls_name = Some("dns.example".into());
+    server.padding = Padding::BlockLength(256);
+    server.tls_config = Some(Arc::new(
+        rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ));
+    let mut conf = ResolvConf::new();
+    conf.servers.push(server);
+    conf.finalize();
+    let resolver = Resolver::from_conf(conf);
+
+    let qname = Name::<Vec<u8>>::from_str("example.com").unwrap();
+    let answer = slings::block_on(resolver.query((&qname, Rtype::A))).unwrap();
+    assert_eq!(answer.header_counts().ancount(), 1);
+}